@@ -12,6 +12,14 @@ if [ -z $GENESIS_AMT ]; then
   GENESIS_AMT="1000000000"
 fi
 
+if [ -z "$LEDGER_CONFIG" ]; then
+  LEDGER_CONFIG="null"
+fi
+
+if [ -z "$HISTORY_CONFIG" ]; then
+  HISTORY_CONFIG="null"
+fi
+
 source .scripts/cap_service.sh # this handles setting the cap id variable, and checks to see if it's already been set
 
 if [[ "$MODE" == "reinstall" ]]; then
@@ -28,6 +36,8 @@ dfx deploy --network $NETWORK wicp \
         principal \"$(dfx identity get-principal)\", 
         0, 
         principal \"$(dfx identity get-principal)\", 
-        principal \"$CAP_ID\"
+        principal \"$CAP_ID\",
+        $LEDGER_CONFIG,
+        $HISTORY_CONFIG
         )" \
     $MODE
\ No newline at end of file
@@ -0,0 +1,88 @@
+/**
+* Module     : auth.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use candid::{CandidType, Deserialize};
+use ic_kit::{ic, Principal};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    // setName, setLogo
+    MetadataAdmin,
+    // setFee, setFeeTo, syncIcpFee, and setLedgerConfig for everything but
+    // the ledger id
+    FeeAdmin,
+    // setPaused
+    Pauser,
+    // reconcileWithdrawals, flushCapQueue, setBlockWatermark, resolveClaim,
+    // resolveWithdrawal
+    ReserveOperator,
+    // setGenesis, exportState and the restore calls
+    Upgrader,
+}
+
+/// Roles granted to principals other than the owner, who holds them all.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Roles {
+    grants: BTreeMap<Principal, BTreeSet<Role>>,
+}
+
+impl Roles {
+    pub fn grant(&mut self, who: Principal, role: Role) {
+        self.grants.entry(who).or_default().insert(role);
+    }
+
+    pub fn revoke(&mut self, who: Principal, role: Role) {
+        if let Some(roles) = self.grants.get_mut(&who) {
+            roles.remove(&role);
+            if roles.is_empty() {
+                self.grants.remove(&who);
+            }
+        }
+    }
+
+    pub fn has(&self, who: &Principal, role: Role) -> bool {
+        self.grants
+            .get(who)
+            .map_or(false, |roles| roles.contains(&role))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Principal, Vec<Role>)> + '_ {
+        self.grants
+            .iter()
+            .map(|(who, roles)| (*who, roles.iter().copied().collect()))
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+struct DefiniteCanisterSettings {
+    controllers: Vec<Principal>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CanisterStatus {
+    settings: DefiniteCanisterSettings,
+}
+
+/// Whether `who` controls this canister. Only controllers get an answer from
+/// `canister_status`, so the canister has to be one of its own controllers.
+pub async fn is_controller(who: Principal) -> bool {
+    let arg = CanisterIdRecord {
+        canister_id: ic::id(),
+    };
+    let status: Result<(CanisterStatus,), _> =
+        ic::call(Principal::management_canister(), "canister_status", (arg,)).await;
+    match status {
+        Ok((status,)) => status.settings.controllers.contains(&who),
+        Err(_) => false,
+    }
+}
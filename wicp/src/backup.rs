@@ -0,0 +1,351 @@
+/**
+* Module     : backup.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use crate::icrc1::{Account, AccountKey};
+use crate::icrc2::Allowance;
+use crate::ledger::BlockHeight;
+use crate::stable::{AllowanceKey, StableNat};
+use crate::{
+    Genesis, PendingClaim, StatsData, Withdrawal, ALLOWS, BALANCES, BLOCKS, CLAIMS, GENESIS,
+    HISTORY, RESTORING, STATS, TXLOG, WITHDRAWALS,
+};
+use candid::{CandidType, Deserialize, Nat};
+use cap_sdk::IndefiniteEvent;
+use ic_kit::Principal;
+use ic_stable_structures::Storable;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::ops::Bound;
+
+const EXPORT_CHUNK: usize = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ExportSection {
+    Stats,
+    Balances,
+    Allowances,
+    UsedBlocks,
+    Minted,
+    CapEvents,
+    Withdrawals,
+    Claims,
+}
+
+impl ExportSection {
+    fn next(self) -> Option<Self> {
+        match self {
+            ExportSection::Stats => Some(ExportSection::Balances),
+            ExportSection::Balances => Some(ExportSection::Allowances),
+            ExportSection::Allowances => Some(ExportSection::UsedBlocks),
+            ExportSection::UsedBlocks => Some(ExportSection::Minted),
+            ExportSection::Minted => Some(ExportSection::CapEvents),
+            ExportSection::CapEvents => Some(ExportSection::Withdrawals),
+            ExportSection::Withdrawals => Some(ExportSection::Claims),
+            ExportSection::Claims => None,
+        }
+    }
+}
+
+/// Where an export page starts: after the key `after` of `section`, encoded
+/// as stored, or at the start of the section.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportCursor {
+    pub section: ExportSection,
+    pub after: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub enum StateEntry {
+    Stats {
+        stats: StatsData,
+        block_watermark: BlockHeight,
+    },
+    Balance(Account, Nat),
+    Allowance(Account, Account, Allowance),
+    UsedBlocks(BlockHeight, BlockHeight),
+    Minted(BlockHeight, Nat),
+    CapEvent(IndefiniteEvent),
+    Withdrawal(Withdrawal),
+    Claim(Principal, PendingClaim),
+}
+
+/// A page of the exported state. `next` is `None` on the last page.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StateChunk {
+    pub entries: Vec<StateEntry>,
+    // where the page starts, so pages are imported in order and only once
+    pub start: ExportCursor,
+    pub next: Option<ExportCursor>,
+    // sha256 of the candid encoding of `entries`
+    pub checksum: Vec<u8>,
+}
+
+/// Progress of a restore, kept across upgrades.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Restore {
+    // where the next imported page must start, `None` once the last one is in
+    pub next: Option<ExportCursor>,
+}
+
+pub fn checksum(entries: &[StateEntry]) -> Vec<u8> {
+    let bytes = candid::encode_one(entries).expect("unable to encode the entries");
+    Sha256::digest(&bytes).to_vec()
+}
+
+pub fn export(cursor: Option<ExportCursor>) -> StateChunk {
+    let mut cursor = cursor.unwrap_or(ExportCursor {
+        section: ExportSection::Stats,
+        after: None,
+    });
+    let start = cursor.clone();
+    let mut entries = Vec::new();
+    let next = loop {
+        let limit = EXPORT_CHUNK - entries.len();
+        let page = _page(&cursor, limit);
+        let full = page.len() == limit;
+        if let Some((key, _)) = page.last() {
+            cursor.after = Some(key.clone());
+        }
+        entries.extend(page.into_iter().map(|(_, entry)| entry));
+        if full {
+            break Some(cursor);
+        }
+        match cursor.section.next() {
+            Some(section) => {
+                cursor = ExportCursor {
+                    section,
+                    after: None,
+                }
+            }
+            None => break None,
+        }
+    };
+    StateChunk {
+        checksum: checksum(&entries),
+        entries,
+        start,
+        next,
+    }
+}
+
+fn _after<K: Storable>(after: &Option<Vec<u8>>) -> Bound<K> {
+    match after {
+        Some(bytes) => Bound::Excluded(K::from_bytes(Cow::Borrowed(bytes))),
+        None => Bound::Unbounded,
+    }
+}
+
+// up to `limit` entries of the cursor's section, with the key they're stored
+// under
+fn _page(cursor: &ExportCursor, limit: usize) -> Vec<(Vec<u8>, StateEntry)> {
+    let after = &cursor.after;
+    match cursor.section {
+        ExportSection::Stats => {
+            if after.is_some() || limit == 0 {
+                return Vec::new();
+            }
+            let entry = StateEntry::Stats {
+                stats: STATS.with(|s| s.borrow().clone()),
+                block_watermark: BLOCKS.with(|b| b.borrow().watermark()),
+            };
+            vec![(Vec::new(), entry)]
+        }
+        ExportSection::Balances => BALANCES.with(|b| {
+            b.borrow()
+                .range((_after::<AccountKey>(after), Bound::Unbounded))
+                .take(limit)
+                .map(|(key, balance)| {
+                    let entry = StateEntry::Balance(key.into(), balance.0);
+                    (key.to_bytes().into_owned(), entry)
+                })
+                .collect()
+        }),
+        ExportSection::Allowances => ALLOWS.with(|a| {
+            a.borrow()
+                .range((_after::<AllowanceKey>(after), Bound::Unbounded))
+                .take(limit)
+                .map(|(key, allowance)| {
+                    let entry =
+                        StateEntry::Allowance(key.owner.into(), key.spender.into(), allowance);
+                    (key.to_bytes().into_owned(), entry)
+                })
+                .collect()
+        }),
+        ExportSection::UsedBlocks => BLOCKS.with(|b| {
+            b.borrow()
+                .ranges_from(_after(after))
+                .take(limit)
+                .map(|(start, end)| {
+                    (
+                        end.to_bytes().into_owned(),
+                        StateEntry::UsedBlocks(start, end),
+                    )
+                })
+                .collect()
+        }),
+        ExportSection::Minted => BLOCKS.with(|b| {
+            b.borrow()
+                .minted_from(_after(after))
+                .take(limit)
+                .map(|(height, tx_id)| {
+                    (
+                        height.to_bytes().into_owned(),
+                        StateEntry::Minted(height, tx_id),
+                    )
+                })
+                .collect()
+        }),
+        ExportSection::CapEvents => TXLOG.with(|t| {
+            t.borrow()
+                .iter_from(_after(after))
+                .take(limit)
+                .map(|(seq, event)| (seq.to_bytes().into_owned(), StateEntry::CapEvent(event)))
+                .collect()
+        }),
+        ExportSection::Withdrawals => WITHDRAWALS.with(|w| {
+            w.borrow()
+                .range(_after(after))
+                .take(limit)
+                .map(|withdrawal| {
+                    (
+                        withdrawal.id.to_bytes().into_owned(),
+                        StateEntry::Withdrawal(withdrawal),
+                    )
+                })
+                .collect()
+        }),
+        ExportSection::Claims => CLAIMS.with(|c| {
+            let after = match after {
+                Some(bytes) => Bound::Excluded(Principal::from_slice(bytes)),
+                None => Bound::Unbounded,
+            };
+            c.borrow()
+                .claims
+                .range((after, Bound::Unbounded))
+                .take(limit)
+                .map(|(owner, claim)| {
+                    (
+                        owner.as_slice().to_vec(),
+                        StateEntry::Claim(*owner, claim.clone()),
+                    )
+                })
+                .collect()
+        }),
+    }
+}
+
+/// Drops the initial balance and the queued genesis of a fresh install, so
+/// the stable maps only hold what `import` adds, and expects the first page.
+pub fn begin() {
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        let keys: Vec<_> = balances.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            balances.remove(&key);
+        }
+    });
+    ALLOWS.with(|a| {
+        let mut allowances = a.borrow_mut();
+        let keys: Vec<_> = allowances.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            allowances.remove(&key);
+        }
+    });
+    TXLOG.with(|t| {
+        let mut outbox = t.borrow_mut();
+        while outbox.pop_front().is_some() {}
+    });
+    CLAIMS.with(|c| c.borrow_mut().claims.clear());
+    RESTORING.with(|r| {
+        *r.borrow_mut() = Some(Restore {
+            next: Some(ExportCursor {
+                section: ExportSection::Stats,
+                after: None,
+            }),
+        })
+    });
+}
+
+/// Applies the page that follows the last one imported. A page sent again or
+/// out of order is refused, so no entry is applied twice.
+pub fn import(chunk: StateChunk) -> Result<(), String> {
+    let expected = RESTORING.with(|r| r.borrow().as_ref().map(|restore| restore.next.clone()));
+    match expected {
+        None => return Err("Error: the canister is not restoring".to_string()),
+        Some(None) => return Err("Error: every chunk was already imported".to_string()),
+        Some(Some(next)) if next != chunk.start => {
+            return Err("Error: chunk out of order or already imported".to_string())
+        }
+        Some(Some(_)) => {}
+    }
+    if checksum(&chunk.entries) != chunk.checksum {
+        return Err("Error: chunk checksum mismatch".to_string());
+    }
+    RESTORING.with(|r| *r.borrow_mut() = Some(Restore { next: chunk.next }));
+    for entry in chunk.entries {
+        match entry {
+            StateEntry::Stats {
+                stats,
+                block_watermark,
+            } => {
+                // the owner and roles stay those of the restoring canister
+                STATS.with(|s| {
+                    let mut current = s.borrow_mut();
+                    let owner = current.owner;
+                    *current = StatsData { owner, ..stats };
+                });
+                BLOCKS.with(|b| b.borrow_mut().set_watermark(block_watermark));
+            }
+            StateEntry::Balance(account, balance) => BALANCES.with(|b| {
+                b.borrow_mut().insert(account.key(), StableNat(balance));
+            }),
+            StateEntry::Allowance(owner, spender, allowance) => ALLOWS.with(|a| {
+                let key = AllowanceKey {
+                    owner: owner.key(),
+                    spender: spender.key(),
+                };
+                a.borrow_mut().insert(key, allowance);
+            }),
+            StateEntry::UsedBlocks(start, end) => {
+                BLOCKS.with(|b| b.borrow_mut().insert_range(start, end))
+            }
+            StateEntry::Minted(height, tx_id) => {
+                BLOCKS.with(|b| b.borrow_mut().set_minted(height, tx_id))
+            }
+            StateEntry::CapEvent(event) => TXLOG.with(|t| t.borrow_mut().push_back(event)),
+            StateEntry::Withdrawal(withdrawal) => {
+                WITHDRAWALS.with(|w| w.borrow_mut().insert(withdrawal))
+            }
+            StateEntry::Claim(owner, claim) => CLAIMS.with(|c| {
+                c.borrow_mut().claims.insert(owner, claim);
+            }),
+        }
+    }
+    Ok(())
+}
+
+/// The local log isn't part of the backup, it restarts after the imported
+/// history size like on canisters upgraded from a release without it.
+pub fn finish() -> Result<(), String> {
+    match RESTORING.with(|r| r.borrow().clone()) {
+        None => return Err("Error: the canister is not restoring".to_string()),
+        Some(Restore { next: Some(_) }) => {
+            return Err("Error: some chunks weren't imported yet".to_string())
+        }
+        Some(Restore { next: None }) => {}
+    }
+    HISTORY.with(|h| h.borrow_mut().clear());
+    // the genesis belongs to the exported canister
+    GENESIS.with(|g| {
+        *g.borrow_mut() = Genesis {
+            record: None,
+            recorded: true,
+        }
+    });
+    RESTORING.with(|r| *r.borrow_mut() = None);
+    Ok(())
+}
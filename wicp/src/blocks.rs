@@ -0,0 +1,167 @@
+/**
+* Module     : blocks.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use crate::ledger::BlockHeight;
+use crate::stable::{self, Memory, StableNat};
+use candid::Nat;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::ops::Bound;
+
+/// Ledger block heights that were already turned into WICP.
+///
+/// Heights are kept as merged inclusive ranges, so runs of consecutive blocks
+/// cost a single entry. Everything below `watermark` counts as used and is no
+/// longer stored, which lets the set be pruned once old blocks can't be minted.
+/// The set lives in stable memory and isn't copied on upgrade.
+pub struct UsedBlocks {
+    watermark: StableCell<BlockHeight, Memory>,
+    // keyed by the end of the range, so the range holding a height is the
+    // first one ending at or after it
+    ranges: StableBTreeMap<BlockHeight, BlockHeight, Memory>,
+    minted: StableBTreeMap<BlockHeight, StableNat, Memory>,
+}
+
+impl UsedBlocks {
+    pub fn init() -> Self {
+        UsedBlocks {
+            watermark: StableCell::init(stable::memory(stable::BLOCK_WATERMARK), 0)
+                .expect("unable to init the block watermark"),
+            ranges: StableBTreeMap::init(stable::memory(stable::BLOCK_RANGES)),
+            minted: StableBTreeMap::init(stable::memory(stable::BLOCK_MINTED)),
+        }
+    }
+
+    pub fn contains(&self, height: BlockHeight) -> bool {
+        if height < self.watermark() {
+            return true;
+        }
+        match self.ranges.range(height..).next() {
+            Some((_, start)) => start <= height,
+            None => false,
+        }
+    }
+
+    /// Marks `height` as used, returns false if it already was.
+    pub fn insert(&mut self, height: BlockHeight) -> bool {
+        if self.contains(height) {
+            return false;
+        }
+        let mut start = height;
+        let mut end = height;
+        if let Some(prev_start) = height
+            .checked_sub(1)
+            .and_then(|prev| self.ranges.remove(&prev))
+        {
+            start = prev_start;
+        }
+        if let Some(next) = height.checked_add(1) {
+            if let Some((next_end, next_start)) = self.ranges.range(next..).next() {
+                if next_start == next {
+                    self.ranges.remove(&next_end);
+                    end = next_end;
+                }
+            }
+        }
+        self.ranges.insert(end, start);
+        true
+    }
+
+    /// Records the WICP transaction that minted the block at `height`.
+    pub fn set_minted(&mut self, height: BlockHeight, tx_id: Nat) {
+        self.minted.insert(height, StableNat(tx_id));
+    }
+
+    pub fn minted(&self, height: BlockHeight) -> Option<Nat> {
+        self.minted.get(&height).map(|tx_id| tx_id.0)
+    }
+
+    pub fn watermark(&self) -> BlockHeight {
+        *self.watermark.get()
+    }
+
+    /// Treats every height below `watermark` as used and drops what was
+    /// stored for them. The watermark never moves back.
+    pub fn set_watermark(&mut self, watermark: BlockHeight) {
+        if watermark <= self.watermark() {
+            return;
+        }
+        self.watermark
+            .set(watermark)
+            .expect("unable to set the block watermark");
+        let dropped: Vec<_> = self.ranges.range(..watermark).map(|(end, _)| end).collect();
+        for end in dropped {
+            self.ranges.remove(&end);
+        }
+        // a range starting below the watermark may reach over it
+        if let Some((end, start)) = self.ranges.range(watermark..).next() {
+            if start < watermark {
+                self.ranges.insert(end, watermark);
+            }
+        }
+        let dropped: Vec<_> = self.minted.range(..watermark).map(|(h, _)| h).collect();
+        for height in dropped {
+            self.minted.remove(&height);
+        }
+    }
+
+    /// Number of used heights stored above the watermark.
+    pub fn len(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|(end, start)| (end - start + 1) as usize)
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = BlockHeight> + '_ {
+        self.ranges.iter().flat_map(|(end, start)| start..=end)
+    }
+
+    /// Stored ranges as `(start, end)`, by end.
+    pub fn ranges_from(
+        &self,
+        end: Bound<BlockHeight>,
+    ) -> impl Iterator<Item = (BlockHeight, BlockHeight)> + '_ {
+        self.ranges
+            .range((end, Bound::Unbounded))
+            .map(|(end, start)| (start, end))
+    }
+
+    /// Adds a range as is, for ranges that were already merged.
+    pub fn insert_range(&mut self, start: BlockHeight, end: BlockHeight) {
+        self.ranges.insert(end, start);
+    }
+
+    pub fn minted_from(
+        &self,
+        height: Bound<BlockHeight>,
+    ) -> impl Iterator<Item = (BlockHeight, Nat)> + '_ {
+        self.minted
+            .range((height, Bound::Unbounded))
+            .map(|(height, tx_id)| (height, tx_id.0))
+    }
+
+    /// Stores the heights kept by the 2.0.0 release, merged into ranges.
+    pub fn migrate(&mut self, heights: impl IntoIterator<Item = BlockHeight>) {
+        let mut heights: Vec<_> = heights.into_iter().collect();
+        heights.sort_unstable();
+        heights.dedup();
+        let mut current: Option<(BlockHeight, BlockHeight)> = None;
+        for height in heights {
+            current = match current {
+                Some((start, end)) if end + 1 == height => Some((start, height)),
+                Some((start, end)) => {
+                    self.ranges.insert(end, start);
+                    Some((height, height))
+                }
+                None => Some((height, height)),
+            };
+        }
+        if let Some((start, end)) = current {
+            self.ranges.insert(end, start);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+/**
+* Module     : dedup.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use candid::{CandidType, Deserialize, Nat};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// How long a request carrying `created_at_time` is remembered.
+pub const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours
+/// Clock difference tolerated between the client and the canister.
+pub const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000; // 2 minutes
+
+pub type TxHash = [u8; 32];
+
+pub enum DedupError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+}
+
+/// Hashes the request as sent by the client, so retries map to the same key.
+pub fn tx_hash<T: CandidType>(request: T) -> TxHash {
+    let bytes = candid::encode_one(request).expect("unable to encode the request");
+    Sha256::digest(&bytes).into()
+}
+
+/// Requests seen within the dedup window, keyed by their `created_at_time`
+/// first so that expired entries can be dropped from the front.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct DedupIndex {
+    entries: BTreeMap<(u64, TxHash), Nat>,
+}
+
+impl DedupIndex {
+    /// Checks a request created at `created_at` against the window at `now`.
+    pub fn check(&self, created_at: u64, hash: TxHash, now: u64) -> Result<(), DedupError> {
+        if created_at.saturating_add(TX_WINDOW + PERMITTED_DRIFT) < now {
+            return Err(DedupError::TooOld);
+        }
+        if created_at > now.saturating_add(PERMITTED_DRIFT) {
+            return Err(DedupError::CreatedInFuture { ledger_time: now });
+        }
+        match self.entries.get(&(created_at, hash)) {
+            Some(tx_id) => Err(DedupError::Duplicate {
+                duplicate_of: tx_id.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn insert(&mut self, created_at: u64, hash: TxHash, tx_id: Nat) {
+        self.entries.insert((created_at, hash), tx_id);
+    }
+
+    /// Forgets the requests that are too old to be accepted anyway.
+    pub fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(TX_WINDOW + PERMITTED_DRIFT);
+        self.entries = self.entries.split_off(&(cutoff, [0; 32]));
+    }
+}
@@ -0,0 +1,207 @@
+/**
+* Module     : history.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use crate::stable::{self, Memory, StableEvent, StableRecord, UserTxKey};
+use candid::{CandidType, Deserialize, Int};
+use cap_sdk::IndefiniteEvent;
+use cap_std::dip20::{Operation, TxRecord};
+use ic_kit::Principal;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use std::convert::TryFrom;
+use std::mem;
+use std::ops::Bound;
+
+/// Transactions recorded by the canister itself, by index, along with the
+/// indexes of the transactions each principal took part in, as caller, sender
+/// or receiver. Both are kept in stable memory, so upgrades don't copy them.
+///
+/// Canisters upgraded from a release without a local log only kept their
+/// history in CAP, so older indexes are missing.
+pub struct TxHistory {
+    records: StableBTreeMap<u64, StableRecord, Memory>,
+    users: StableBTreeMap<UserTxKey, (), Memory>,
+}
+
+impl TxHistory {
+    pub fn init() -> Self {
+        TxHistory {
+            records: StableBTreeMap::init(stable::memory(stable::HISTORY_RECORDS)),
+            users: StableBTreeMap::init(stable::memory(stable::USER_TXS)),
+        }
+    }
+
+    /// Drops every record.
+    pub fn clear(&mut self) {
+        let indexes: Vec<_> = self.records.iter().map(|(index, _)| index).collect();
+        for index in indexes {
+            self.records.remove(&index);
+        }
+        let keys: Vec<_> = self.users.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            self.users.remove(&key);
+        }
+    }
+
+    /// Stores a record under its own index.
+    pub fn push(&mut self, record: TxRecord) {
+        let index = u64::try_from(&record.index.0).expect("transaction index out of range");
+        let mut users = vec![record.from, record.to];
+        users.extend(record.caller);
+        users.sort();
+        users.dedup();
+        for user in users {
+            self.users.insert(UserTxKey { user, index }, ());
+        }
+        self.records.insert(index, StableRecord(record));
+    }
+
+    pub fn get(&self, index: usize) -> Option<TxRecord> {
+        self.records.get(&(index as u64)).map(|record| record.0)
+    }
+
+    /// Up to `limit` transactions from `start` on, skipping the missing ones.
+    pub fn range(&self, start: usize, limit: usize) -> Vec<TxRecord> {
+        self.records
+            .range(start as u64..)
+            .take(limit)
+            .map(|(_, record)| record.0)
+            .collect()
+    }
+
+    /// Indexes of the transactions `who` took part in, in order.
+    pub fn user_txs(&self, who: Principal) -> impl Iterator<Item = usize> + '_ {
+        self.users
+            .range(UserTxKey::first_of(who)..)
+            .take_while(move |(key, _)| key.user == who)
+            .map(|(key, _)| key.index as usize)
+    }
+}
+
+/// Destination of committed transactions.
+pub trait HistorySink {
+    fn record(&self, record: &TxRecord);
+}
+
+/// History sinks that can be selected at install time.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum HistoryBackend {
+    Cap,
+    Local,
+    Noop,
+}
+
+/// Sinks used when none are given at install time. Builds without the `cap`
+/// feature don't need a CAP router.
+pub fn default_backends() -> Vec<HistoryBackend> {
+    if cfg!(feature = "cap") {
+        vec![HistoryBackend::Cap, HistoryBackend::Local]
+    } else {
+        vec![HistoryBackend::Local]
+    }
+}
+
+pub struct NoopSink;
+
+impl HistorySink for NoopSink {
+    fn record(&self, _record: &TxRecord) {}
+}
+
+/// Writes every record to each of its sinks in turn.
+pub struct FanOut(pub Vec<Box<dyn HistorySink>>);
+
+impl HistorySink for FanOut {
+    fn record(&self, record: &TxRecord) {
+        for sink in &self.0 {
+            sink.record(record);
+        }
+    }
+}
+
+/// Narrows down a user's transactions, every field left out matches all.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct TxFilter {
+    pub operations: Option<Vec<Operation>>,
+    // nanoseconds, inclusive
+    pub start_time: Option<u64>,
+    // nanoseconds, exclusive
+    pub end_time: Option<u64>,
+}
+
+impl TxFilter {
+    pub fn matches(&self, record: &TxRecord) -> bool {
+        if let Some(operations) = &self.operations {
+            let operation = mem::discriminant(&record.operation);
+            if !operations
+                .iter()
+                .any(|op| mem::discriminant(op) == operation)
+            {
+                return false;
+            }
+        }
+        if let Some(start) = self.start_time {
+            if record.timestamp < Int::from(start) {
+                return false;
+            }
+        }
+        if let Some(end) = self.end_time {
+            if record.timestamp >= Int::from(end) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Events waiting to be sent to CAP, kept in stable memory so a pending queue
+/// doesn't have to be copied on upgrade.
+pub struct Outbox {
+    events: StableBTreeMap<u64, StableEvent, Memory>,
+    // sequence number of the next event pushed
+    tail: StableCell<u64, Memory>,
+}
+
+impl Outbox {
+    pub fn init() -> Self {
+        Outbox {
+            events: StableBTreeMap::init(stable::memory(stable::OUTBOX_EVENTS)),
+            tail: StableCell::init(stable::memory(stable::OUTBOX_TAIL), 0)
+                .expect("unable to init the outbox tail"),
+        }
+    }
+
+    pub fn push_back(&mut self, event: IndefiniteEvent) {
+        let seq = *self.tail.get();
+        self.events.insert(seq, StableEvent(event));
+        self.tail
+            .set(seq + 1)
+            .expect("unable to set the outbox tail");
+    }
+
+    pub fn front(&self) -> Option<IndefiniteEvent> {
+        self.events.iter().next().map(|(_, event)| event.0)
+    }
+
+    pub fn pop_front(&mut self) -> Option<IndefiniteEvent> {
+        let (seq, _) = self.events.iter().next()?;
+        self.events.remove(&seq).map(|event| event.0)
+    }
+
+    /// Queued events with their sequence numbers, in order.
+    pub fn iter_from(&self, seq: Bound<u64>) -> impl Iterator<Item = (u64, IndefiniteEvent)> + '_ {
+        self.events
+            .range((seq, Bound::Unbounded))
+            .map(|(seq, event)| (seq, event.0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
@@ -0,0 +1,105 @@
+/**
+* Module     : icrc1.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use crate::dedup::DedupError;
+use crate::ledger::{AccountIdentifier, Subaccount, DEFAULT_SUBACCOUNT};
+use candid::{CandidType, Deserialize, Int, Nat};
+use ic_kit::Principal;
+use serde::Serialize;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+/// Key of an account in the balance and allowance maps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AccountKey(pub Principal, pub Subaccount);
+
+impl Account {
+    /// `None` and the all-zero subaccount both being the default account,
+    /// they share the same key.
+    pub fn key(&self) -> AccountKey {
+        AccountKey(self.owner, self.subaccount.unwrap_or(DEFAULT_SUBACCOUNT))
+    }
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+impl From<AccountKey> for Account {
+    fn from(key: AccountKey) -> Self {
+        Account {
+            owner: key.0,
+            subaccount: Some(key.1),
+        }
+    }
+}
+
+impl From<&Account> for AccountIdentifier {
+    fn from(account: &Account) -> Self {
+        AccountIdentifier::new(
+            &account.owner,
+            &account.subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
+        )
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+impl From<DedupError> for TransferError {
+    fn from(error: DedupError) -> Self {
+        match error {
+            DedupError::TooOld => TransferError::TooOld,
+            DedupError::CreatedInFuture { ledger_time } => {
+                TransferError::CreatedInFuture { ledger_time }
+            }
+            DedupError::Duplicate { duplicate_of } => TransferError::Duplicate { duplicate_of },
+        }
+    }
+}
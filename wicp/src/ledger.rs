@@ -0,0 +1,443 @@
+/**
+* Module     : ledger.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use candid::types::{Function, Serializer, Type};
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{CandidType, Deserialize, Func};
+use ic_cdk::api::call::RejectionCode;
+use ic_kit::{ic, Principal};
+use serde::Serialize;
+use sha2::{Digest, Sha224};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fmt;
+
+pub type BlockIndex = u64;
+pub type BlockHeight = BlockIndex;
+
+/* ACCOUNTS */
+
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Subaccount(pub [u8; 32]);
+
+pub const DEFAULT_SUBACCOUNT: Subaccount = Subaccount([0; 32]);
+
+impl From<&Principal> for Subaccount {
+    fn from(principal: &Principal) -> Self {
+        let bytes = principal.as_slice();
+        let mut subaccount = [0u8; 32];
+        subaccount[0] = bytes.len() as u8;
+        subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+        Subaccount(subaccount)
+    }
+}
+
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct AccountIdentifier(pub [u8; 32]);
+
+impl AccountIdentifier {
+    pub fn new(owner: &Principal, subaccount: &Subaccount) -> Self {
+        let mut hasher = Sha224::new();
+        hasher.update(b"\x0Aaccount-id");
+        hasher.update(owner.as_slice());
+        hasher.update(&subaccount.0[..]);
+        let hash: [u8; 28] = hasher.finalize().into();
+
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&hash);
+        let checksum = crc.finalize().to_be_bytes();
+
+        let mut result = [0u8; 32];
+        result[0..4].copy_from_slice(&checksum[..]);
+        result[4..32].copy_from_slice(&hash[..]);
+        Self(result)
+    }
+
+    /// Parses a hex account identifier and checks its CRC32 checksum.
+    pub fn from_hex(hex_str: &str) -> Result<Self, String> {
+        let bytes = hex::decode(hex_str).map_err(|e| e.to_string())?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| "Error: account identifier must be 32 bytes".to_string())?;
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(&bytes[4..]);
+        if crc.finalize().to_be_bytes() != bytes[0..4] {
+            return Err("Error: account identifier checksum mismatch".to_string());
+        }
+        Ok(Self(bytes))
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.0)
+    }
+}
+
+impl fmt::Display for AccountIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
+    }
+}
+
+/* TOKENS */
+
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Tokens {
+    e8s: u64,
+}
+
+impl Tokens {
+    pub const fn from_e8s(e8s: u64) -> Self {
+        Self { e8s }
+    }
+
+    pub const fn e8s(&self) -> u64 {
+        self.e8s
+    }
+
+    pub fn checked_sub(self, other: Tokens) -> Option<Tokens> {
+        self.e8s.checked_sub(other.e8s).map(Tokens::from_e8s)
+    }
+}
+
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Memo(pub u64);
+
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub struct Timestamp {
+    pub timestamp_nanos: u64,
+}
+
+/* BLOCKS */
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Mint {
+        to: AccountIdentifier,
+        amount: Tokens,
+    },
+    Burn {
+        from: AccountIdentifier,
+        amount: Tokens,
+    },
+    Transfer {
+        from: AccountIdentifier,
+        to: AccountIdentifier,
+        amount: Tokens,
+        fee: Tokens,
+    },
+    Approve {
+        from: AccountIdentifier,
+        spender: AccountIdentifier,
+        fee: Tokens,
+    },
+    TransferFrom {
+        from: AccountIdentifier,
+        to: AccountIdentifier,
+        spender: AccountIdentifier,
+        amount: Tokens,
+        fee: Tokens,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Transaction {
+    pub memo: Memo,
+    pub icrc1_memo: Option<Vec<u8>>,
+    pub operation: Option<Operation>,
+    pub created_at_time: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Block {
+    pub transaction: Transaction,
+    pub timestamp: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: BlockIndex,
+    pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockRange {
+    pub blocks: Vec<Block>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum GetBlocksError {
+    BadFirstBlockIndex {
+        requested_index: BlockIndex,
+        first_valid_index: BlockIndex,
+    },
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+pub type GetBlocksResult = Result<BlockRange, GetBlocksError>;
+
+/// Reference to the `get_blocks` method of an archive canister, as handed out
+/// by `query_blocks`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(transparent)]
+pub struct QueryArchiveFn(pub Func);
+
+impl CandidType for QueryArchiveFn {
+    fn _ty() -> Type {
+        Type::Func(Function {
+            modes: vec![candid::parser::types::FuncMode::Query],
+            args: vec![GetBlocksArgs::ty()],
+            rets: vec![GetBlocksResult::ty()],
+        })
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocksRange {
+    pub start: BlockIndex,
+    pub length: u64,
+    pub callback: QueryArchiveFn,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct QueryBlocksResponse {
+    pub chain_length: u64,
+    pub blocks: Vec<Block>,
+    pub first_block_index: BlockIndex,
+    pub archived_blocks: Vec<ArchivedBlocksRange>,
+}
+
+/* TRANSFERS */
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArgs {
+    pub memo: Memo,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: AccountIdentifier,
+    pub created_at_time: Option<Timestamp>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: BlockIndex },
+}
+
+pub type TransferResult = Result<BlockIndex, TransferError>;
+
+/// Argument of `transaction_notification`, which the ledger calls on the
+/// receiving canister after a `notify_dfx`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransactionNotification {
+    pub from: Principal,
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub block_height: BlockHeight,
+    pub amount: Tokens,
+    pub memo: Memo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountBalanceArgs {
+    pub account: AccountIdentifier,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFeeArgs {}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFee {
+    pub transfer_fee: Tokens,
+}
+
+/* CALLS */
+
+pub type CallError = (RejectionCode, String);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    // neither the ledger nor its archives hold the block
+    NotFound,
+    LedgerUnreachable,
+    ArchiveUnreachable,
+    DecodeFailure,
+}
+
+enum RawCallError {
+    Rejected,
+    Decode,
+}
+
+/// Calls `method` without trapping when the reply doesn't decode.
+async fn call_raw<A, R>(canister: Principal, method: &str, args: A) -> Result<R, RawCallError>
+where
+    A: ArgumentEncoder,
+    R: for<'a> ArgumentDecoder<'a>,
+{
+    let args = candid::encode_args(args).map_err(|_| RawCallError::Decode)?;
+    let bytes = ic_cdk::api::call::call_raw(canister, method, args, 0)
+        .await
+        .map_err(|_| RawCallError::Rejected)?;
+    candid::decode_args(&bytes).map_err(|_| RawCallError::Decode)
+}
+
+/* ARCHIVES */
+
+struct ArchiveRange {
+    end: BlockIndex,
+    callback: Func,
+}
+
+#[derive(Default)]
+struct ArchiveCache {
+    ledger: Option<Principal>,
+    // archived ranges by their first block, ends are exclusive
+    ranges: BTreeMap<BlockIndex, ArchiveRange>,
+}
+
+thread_local! {
+    static ARCHIVES: RefCell<ArchiveCache> = RefCell::new(ArchiveCache::default());
+}
+
+fn cached_archive(ledger: Principal, height: BlockHeight) -> Option<Func> {
+    ARCHIVES.with(|a| {
+        let cache = a.borrow();
+        if cache.ledger != Some(ledger) {
+            return None;
+        }
+        match cache.ranges.range(..=height).next_back() {
+            Some((_, range)) if height < range.end => Some(range.callback.clone()),
+            _ => None,
+        }
+    })
+}
+
+fn cache_archives(ledger: Principal, archived: Vec<ArchivedBlocksRange>) {
+    ARCHIVES.with(|a| {
+        let mut cache = a.borrow_mut();
+        if cache.ledger != Some(ledger) {
+            *cache = ArchiveCache {
+                ledger: Some(ledger),
+                ranges: BTreeMap::new(),
+            };
+        }
+        for range in archived {
+            cache.ranges.insert(
+                range.start,
+                ArchiveRange {
+                    end: range.start + range.length,
+                    callback: range.callback.0,
+                },
+            );
+        }
+    });
+}
+
+fn clear_archives() {
+    ARCHIVES.with(|a| *a.borrow_mut() = ArchiveCache::default());
+}
+
+async fn get_archived_block(archive: Func, height: BlockHeight) -> Result<Block, BlockError> {
+    let args = GetBlocksArgs {
+        start: height,
+        length: 1,
+    };
+    let (result,): (GetBlocksResult,) = call_raw(archive.principal, &archive.method, (args,))
+        .await
+        .map_err(|err| match err {
+            RawCallError::Rejected => BlockError::ArchiveUnreachable,
+            RawCallError::Decode => BlockError::DecodeFailure,
+        })?;
+    match result {
+        Ok(range) => range.blocks.into_iter().next().ok_or(BlockError::NotFound),
+        // the archive doesn't cover this height (any more)
+        Err(GetBlocksError::BadFirstBlockIndex { .. }) => Err(BlockError::NotFound),
+        Err(GetBlocksError::Other { .. }) => Err(BlockError::ArchiveUnreachable),
+    }
+}
+
+/// Fetches a single block, from the archive canister that holds it when the
+/// ledger no longer does. Archive ranges are cached, a cached archive that
+/// fails is dropped and the block is looked up again through the ledger.
+pub async fn get_block(ledger: Principal, height: BlockHeight) -> Result<Block, BlockError> {
+    if let Some(archive) = cached_archive(ledger, height) {
+        match get_archived_block(archive, height).await {
+            Ok(block) => return Ok(block),
+            Err(BlockError::DecodeFailure) => return Err(BlockError::DecodeFailure),
+            Err(_) => clear_archives(),
+        }
+    }
+
+    let args = GetBlocksArgs {
+        start: height,
+        length: 1,
+    };
+    let (response,): (QueryBlocksResponse,) = call_raw(ledger, "query_blocks", (args,))
+        .await
+        .map_err(|err| match err {
+            RawCallError::Rejected => BlockError::LedgerUnreachable,
+            RawCallError::Decode => BlockError::DecodeFailure,
+        })?;
+    if let Some(block) = response.blocks.into_iter().next() {
+        return Ok(block);
+    }
+    if height >= response.first_block_index {
+        return Err(BlockError::NotFound);
+    }
+
+    // an archived block comes back as the range of the archive holding it
+    cache_archives(ledger, response.archived_blocks);
+    match cached_archive(ledger, height) {
+        Some(archive) => get_archived_block(archive, height).await,
+        None => Err(BlockError::NotFound),
+    }
+}
+
+pub async fn transfer(ledger: Principal, args: TransferArgs) -> Result<TransferResult, CallError> {
+    let (result,): (TransferResult,) = ic::call(ledger, "transfer", (args,)).await?;
+    Ok(result)
+}
+
+pub async fn account_balance(
+    ledger: Principal,
+    account: AccountIdentifier,
+) -> Result<Tokens, CallError> {
+    let (balance,): (Tokens,) =
+        ic::call(ledger, "account_balance", (AccountBalanceArgs { account },)).await?;
+    Ok(balance)
+}
+
+pub async fn transfer_fee(ledger: Principal) -> Result<Tokens, CallError> {
+    let (fee,): (TransferFee,) = ic::call(ledger, "transfer_fee", (TransferFeeArgs {},)).await?;
+    Ok(fee.transfer_fee)
+}
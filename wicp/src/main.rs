@@ -5,31 +5,49 @@
 * Maintainer : Ossian Mapes <oz@fleek.co>
 * Stability  : Experimental
 */
+mod auth;
+mod backup;
+mod blocks;
+mod dedup;
+mod history;
+mod icrc1;
+mod icrc2;
+mod ledger;
+mod stable;
+mod upgrade;
+
+use auth::{Role, Roles};
+use backup::{ExportCursor, Restore, StateChunk};
+use blocks::UsedBlocks;
 use candid::{candid_method, CandidType, Deserialize, Int, Nat};
 use cap_sdk::{handshake, insert, CapEnv, Event, IndefiniteEvent, TypedEvent};
 use cap_std::dip20::cap::DIP20Details;
 use cap_std::dip20::{Operation, TransactionStatus, TxRecord};
-use dfn_core::api::call_with_cleanup;
-use dfn_protobuf::protobuf;
+use dedup::{tx_hash, DedupError, DedupIndex, TxHash};
+use futures::future::join_all;
+use history::{
+    default_backends, FanOut, HistoryBackend, HistorySink, NoopSink, Outbox, TxFilter, TxHistory,
+};
 use ic_cdk_macros::*;
 use ic_kit::{ic, Principal};
-use ic_types::{CanisterId, PrincipalId};
-use ledger_canister::{
-    account_identifier::{AccountIdentifier, Subaccount},
-    tokens::Tokens,
-    BlockHeight, BlockRes, Memo, Operation as Operate, SendArgs,
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use icrc1::{Account, AccountKey, StandardRecord, TransferArg, Value};
+use icrc2::{
+    Allowance, AllowanceArgs, ApproveArgs, ApproveError, TransferFromArgs, TransferFromError,
+};
+use ledger::{
+    AccountIdentifier, BlockError, BlockHeight, BlockIndex, CallError, Memo, Operation as Operate,
+    Subaccount, Timestamp, Tokens, TransactionNotification, TransferArgs, TransferError,
+    TransferResult, DEFAULT_SUBACCOUNT,
 };
+use stable::{AllowanceKey, Memory, StableNat, UserTxKey};
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::convert::Into;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::{Into, TryFrom};
 use std::iter::FromIterator;
+use std::ops::Bound;
 use std::string::String;
 
-#[derive(CandidType, Default, Deserialize, Clone)]
-pub struct TxLog {
-    pub ie_records: VecDeque<IndefiniteEvent>,
-}
-
 #[allow(non_snake_case)]
 #[derive(Deserialize, CandidType, Clone, Debug)]
 struct Metadata {
@@ -43,7 +61,7 @@ struct Metadata {
 }
 
 #[derive(Deserialize, CandidType, Clone, Debug)]
-struct StatsData {
+pub struct StatsData {
     logo: String,
     name: String,
     symbol: String,
@@ -85,36 +103,227 @@ struct TokenInfo {
     cycles: u64,
 }
 
-#[derive(Deserialize, CandidType, Clone, Debug)]
-struct Genesis {
-    caller: Option<Principal>,
-    op: Operation,
-    from: Principal,
-    to: Principal,
+/// Mint of the initial supply, the first transaction of the history.
+#[derive(Deserialize, CandidType, Clone, Debug, Default)]
+pub struct Genesis {
+    // unknown on canisters upgraded from a release that didn't keep it
+    record: Option<TxRecord>,
+    // whether it was queued for CAP, which only ever happens once
+    recorded: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WithdrawalStatus {
+    // burned, the ledger transfer has not been answered yet
+    Pending,
+    // the ledger returned the block index of the payout
+    Sent,
+    // the payout block was read back from the ledger
+    Confirmed,
+    // the ledger rejected the payout, the burned amount was credited back
+    Refunded,
+    // too old for the ledger to deduplicate, or the block doesn't match the
+    // payout, waits for resolveWithdrawal
+    NeedsReview,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Withdrawal {
+    id: u64,
+    owner: Principal,
     amount: Nat,
-    fee: Nat,
+    args: TransferArgs,
+    status: WithdrawalStatus,
+    block_index: Option<BlockIndex>,
+    // index of the burn, once recorded
+    tx_index: Option<Nat>,
     timestamp: u64,
-    status: TransactionStatus,
 }
 
-impl Default for Genesis {
+/// Withdrawals by id, along with the ids of each owner's withdrawals and the
+/// total still pending. All are kept in stable memory, so upgrades don't copy
+/// them.
+pub struct WithdrawalJournal {
+    withdrawals: StableBTreeMap<u64, Withdrawal, Memory>,
+    owners: StableBTreeMap<UserTxKey, (), Memory>,
+    pending: StableCell<StableNat, Memory>,
+}
+
+impl WithdrawalJournal {
+    fn init() -> Self {
+        WithdrawalJournal {
+            withdrawals: StableBTreeMap::init(stable::memory(stable::WITHDRAWALS)),
+            owners: StableBTreeMap::init(stable::memory(stable::USER_WITHDRAWALS)),
+            pending: StableCell::init(
+                stable::memory(stable::WITHDRAWALS_PENDING),
+                StableNat(Nat::from(0)),
+            )
+            .expect("unable to init the pending withdrawals"),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        self.withdrawals.len()
+    }
+
+    fn get(&self, id: u64) -> Option<Withdrawal> {
+        self.withdrawals.get(&id)
+    }
+
+    /// Adds or updates a withdrawal, keeping the owner index and the pending
+    /// total in step.
+    fn insert(&mut self, withdrawal: Withdrawal) {
+        let mut pending = self.pending.get().0.clone();
+        if withdrawal.status == WithdrawalStatus::Pending {
+            pending += withdrawal.amount.clone();
+        }
+        let key = UserTxKey {
+            user: withdrawal.owner,
+            index: withdrawal.id,
+        };
+        if let Some(previous) = self.withdrawals.insert(withdrawal.id, withdrawal) {
+            if previous.status == WithdrawalStatus::Pending {
+                pending -= previous.amount;
+            }
+        }
+        self.owners.insert(key, ());
+        self.pending
+            .set(StableNat(pending))
+            .expect("unable to set the pending withdrawals");
+    }
+
+    /// Sum of the withdrawals still pending.
+    fn pending(&self) -> Nat {
+        self.pending.get().0.clone()
+    }
+
+    /// Withdrawals of `who`, in id order.
+    fn of(&self, who: Principal) -> impl Iterator<Item = Withdrawal> + '_ {
+        self.owners
+            .range(UserTxKey::first_of(who)..)
+            .take_while(move |(key, _)| key.user == who)
+            .filter_map(move |(key, _)| self.withdrawals.get(&key.index))
+    }
+
+    /// Withdrawals from `id` on, then the ones before it.
+    fn iter_from(&self, id: u64) -> impl Iterator<Item = Withdrawal> + '_ {
+        self.withdrawals
+            .range(id..)
+            .chain(self.withdrawals.range(..id))
+            .map(|(_, withdrawal)| withdrawal)
+    }
+
+    /// Withdrawals after `id`, in id order.
+    fn range(&self, id: Bound<u64>) -> impl Iterator<Item = Withdrawal> + '_ {
+        self.withdrawals
+            .range((id, Bound::Unbounded))
+            .map(|(_, withdrawal)| withdrawal)
+    }
+}
+
+/// Sweep of a deposit subaccount whose outcome isn't known yet. It is retried
+/// with the same arguments, so the ledger answers with the block it already
+/// made instead of paying twice.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingClaim {
+    args: TransferArgs,
+    // too old for the ledger to deduplicate, waits for resolveClaim
+    needs_review: bool,
+}
+
+#[derive(CandidType, Default, Deserialize, Clone)]
+pub struct ClaimJournal {
+    claims: BTreeMap<Principal, PendingClaim>,
+}
+
+impl ClaimJournal {
+    /// Claims after `owner`, then the ones up to it.
+    fn iter_after(
+        &self,
+        owner: Option<Principal>,
+    ) -> impl Iterator<Item = (&Principal, &PendingClaim)> + '_ {
+        let after = match owner {
+            Some(owner) => Bound::Excluded(owner),
+            None => Bound::Unbounded,
+        };
+        let wrapped = owner.map(|owner| self.claims.range(..=owner));
+        self.claims
+            .range((after, Bound::Unbounded))
+            .chain(wrapped.into_iter().flatten())
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct ReserveStatus {
+    total_supply: Nat,
+    reserve: Nat,
+    surplus: Nat,
+    deficit: Nat,
+    pending_withdrawals: Nat,
+    fees_collected: Nat,
+    icp_fees_spent: Nat,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ReserveData {
+    fees_collected: Nat,
+    icp_fees_spent: Nat,
+    history: VecDeque<ReserveStatus>,
+}
+
+impl Default for ReserveData {
     fn default() -> Self {
-        Genesis {
-            caller: None,
-            op: Operation::Mint,
-            from: Principal::anonymous(),
-            to: Principal::anonymous(),
-            amount: Nat::from(0),
-            fee: Nat::from(0),
-            timestamp: 0,
-            status: TransactionStatus::Succeeded,
+        ReserveData {
+            fees_collected: Nat::from(0),
+            icp_fees_spent: Nat::from(0),
+            history: VecDeque::new(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerConfig {
+    ledger_id: Principal,
+    icp_fee: Tokens,
+    threshold: Tokens,
+    // keep icp_fee in sync with the ledger's transfer_fee
+    sync_fee: bool,
+}
+
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            ledger_id: Principal::from_text(LEDGER_CANISTER_ID).unwrap(),
+            icp_fee: DEFAULT_ICPFEE,
+            threshold: DEFAULT_THRESHOLD,
+            sync_fee: false,
         }
     }
 }
 
-type Balances = HashMap<Principal, Nat>;
-type Allowances = HashMap<Principal, HashMap<Principal, Nat>>;
-type UsedBlocks = HashSet<BlockHeight>;
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum WithdrawDestination {
+    // hex account identifier, checksum included
+    AccountId(String),
+    Principal(Principal, Option<Subaccount>),
+    Account(Account),
+}
+
+/// State of the CAP outbox, counters are kept since the last upgrade.
+#[derive(CandidType, Clone, Debug, Default)]
+struct CapQueueStatus {
+    queued: usize,
+    flushing: bool,
+    indexed: u64,
+    failures: u64,
+    consecutive_failures: u32,
+    // no flush is attempted before this time
+    next_retry: u64,
+}
+
+type Balances = StableBTreeMap<AccountKey, StableNat, Memory>;
+type Allowances = StableBTreeMap<AllowanceKey, Allowance, Memory>;
 
 #[derive(CandidType, Debug, PartialEq)]
 pub enum TxError {
@@ -127,24 +336,56 @@ pub enum TxError {
     ErrorOperationStyle,
     ErrorTo,
     Other,
+    BlockNotFound,
+    ArchiveUnreachable,
+    DecodeFailure,
 }
 
 pub type TxReceipt = Result<Nat, TxError>;
 
 thread_local! {
     /*    stable    */
-    static BALANCES: RefCell<HashMap<Principal, Nat>> = RefCell::new(HashMap::default());
-    static ALLOWS: RefCell<HashMap<Principal, HashMap<Principal, Nat>>> = RefCell::new(HashMap::default());
-    static BLOCKS: RefCell<HashSet<BlockHeight>> = RefCell::new(HashSet::default());
+    static BALANCES: RefCell<Balances> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::BALANCES)));
+    static ALLOWS: RefCell<Allowances> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::ALLOWANCES)));
+    static BLOCKS: RefCell<UsedBlocks> = RefCell::new(UsedBlocks::init());
     static STATS: RefCell<StatsData> = RefCell::new(StatsData::default());
-    static TXLOG: RefCell<TxLog> = RefCell::new(TxLog::default());
-    /*   flexible   */
+    static TXLOG: RefCell<Outbox> = RefCell::new(Outbox::init());
+    static HISTORY: RefCell<TxHistory> = RefCell::new(TxHistory::init());
+    static SINKS: RefCell<Vec<HistoryBackend>> = RefCell::new(default_backends());
+    static WITHDRAWALS: RefCell<WithdrawalJournal> = RefCell::new(WithdrawalJournal::init());
+    static CLAIMS: RefCell<ClaimJournal> = RefCell::new(ClaimJournal::default());
+    static RESERVE: RefCell<ReserveData> = RefCell::new(ReserveData::default());
+    static CONFIG: RefCell<LedgerConfig> = RefCell::new(LedgerConfig::default());
+    static DEDUP: RefCell<DedupIndex> = RefCell::new(DedupIndex::default());
     static GENESIS: RefCell<Genesis> = RefCell::new(Genesis::default());
+    static ROLES: RefCell<Roles> = RefCell::new(Roles::default());
+    static PAUSED: RefCell<bool> = RefCell::new(false);
+    static RESTORING: RefCell<Option<Restore>> = RefCell::new(None);
+    /*   flexible   */
+    static RECONCILING: RefCell<bool> = RefCell::new(false);
+    static LAST_RECONCILE: RefCell<u64> = RefCell::new(0);
+    static RECONCILE_CURSOR: RefCell<u64> = RefCell::new(0);
+    static RECONCILING_CLAIMS: RefCell<bool> = RefCell::new(false);
+    static CLAIMS_CURSOR: RefCell<Option<Principal>> = RefCell::new(None);
+    static CAP_STATUS: RefCell<CapQueueStatus> = RefCell::new(CapQueueStatus::default());
 }
 
-const LEDGER_CANISTER_ID: CanisterId = CanisterId::from_u64(2);
-const THRESHOLD: Tokens = Tokens::from_e8s(0); // 0;
-const ICPFEE: Tokens = Tokens::from_e8s(10000);
+const LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+const DEFAULT_THRESHOLD: Tokens = Tokens::from_e8s(0); // 0;
+const DEFAULT_ICPFEE: Tokens = Tokens::from_e8s(10000);
+const RECONCILE_INTERVAL: u64 = 10 * 60 * 1_000_000_000; // 10 minutes
+const RECONCILE_BATCH: usize = 20;
+const RESERVE_HISTORY_SIZE: usize = 1000;
+const MINT_BATCH_CHUNK: usize = 50;
+const MAX_MINT_BATCH: usize = 500;
+const MAX_MEMO_SIZE: usize = 32;
+// "WICP" and the version of the memo layout
+const MINT_MEMO_PREFIX: &[u8] = b"WICP\x01";
+const CAP_FLUSH_BATCH: usize = 50;
+const CAP_RETRY_BASE: u64 = 5 * 1_000_000_000; // 5 seconds
+const CAP_RETRY_MAX: u64 = 10 * 60 * 1_000_000_000; // 10 minutes
 
 #[init]
 #[candid_method(init)]
@@ -158,7 +399,17 @@ fn init(
     fee: Nat,
     fee_to: Principal,
     cap: Principal,
+    ledger_config: Option<LedgerConfig>,
+    history: Option<Vec<HistoryBackend>>,
 ) {
+    CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        *config = ledger_config.unwrap_or_default();
+    });
+    SINKS.with(|s| {
+        let mut sinks = s.borrow_mut();
+        *sinks = history.unwrap_or_else(default_backends);
+    });
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.logo = logo;
@@ -172,252 +423,150 @@ fn init(
         stats.history_size = 1;
         stats.deploy_time = ic::time();
     });
-    handshake(1_000_000_000_000, Some(cap));
+    if _has_backend(HistoryBackend::Cap) {
+        handshake(1_000_000_000_000, Some(cap));
+    }
     _balance_ins(owner, initial_supply.clone());
 
+    let record = TxRecord {
+        caller: Some(owner),
+        index: Nat::from(0),
+        from: Principal::from_text("aaaaa-aa").unwrap(),
+        to: owner,
+        amount: initial_supply,
+        fee,
+        timestamp: Int::from(ic::time()),
+        status: TransactionStatus::Succeeded,
+        operation: Operation::Mint,
+    };
+    if _has_backend(HistoryBackend::Local) {
+        LocalSink.record(&record);
+    }
+    // CAP can't be called during init, the heartbeat sends the genesis once
+    // the handshake is done
+    let recorded = _has_backend(HistoryBackend::Cap);
+    if recorded {
+        _cap_enqueue(&record);
+    }
     GENESIS.with(|g| {
         let mut genesis = g.borrow_mut();
-        genesis.caller = Some(owner);
-        genesis.op = Operation::Mint;
-        genesis.from = Principal::from_text("aaaaa-aa").unwrap();
-        genesis.to = owner;
-        genesis.amount = initial_supply;
-        genesis.fee = fee;
-        genesis.timestamp = ic::time();
-        genesis.status = TransactionStatus::Succeeded;
+        genesis.record = Some(record);
+        genesis.recorded = recorded;
     });
 }
 
-#[update(name = "transfer")]
+#[update(name = "transfer", guard = _is_live)]
 #[candid_method(update)]
-async fn transfer(to: Principal, value: Nat) -> TxReceipt {
-    let from = ic::caller();
-    let fee = _get_fee();
-    if balance_of(from) < value.clone() + fee.clone() {
-        return Err(TxError::InsufficientBalance);
-    }
-    _charge_fee(from);
-    _transfer(from, to, value.clone());
-    _history_inc();
+fn transfer(to: Principal, value: Nat) -> TxReceipt {
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: to.into(),
+        amount: value,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let record = match _icrc1_transfer(ic::caller().into(), &arg) {
+        Ok(record) => record,
+        Err(icrc1::TransferError::InsufficientFunds { .. }) => {
+            return Err(TxError::InsufficientBalance)
+        }
+        Err(_) => return Err(TxError::Other),
+    };
+    Ok(add_record(record))
+}
 
-    add_record(
-        Some(from),
-        Operation::Transfer,
-        from,
-        to,
-        value,
-        fee.clone(),
-        ic::time(),
-        TransactionStatus::Succeeded,
-    )
-    .await
+#[update(name = "icrc1_transfer", guard = _is_live)]
+#[candid_method(update, rename = "icrc1_transfer")]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, icrc1::TransferError> {
+    let from = Account {
+        owner: ic::caller(),
+        subaccount: arg.from_subaccount,
+    };
+    let record = _icrc1_transfer(from, &arg)?;
+    Ok(add_record(record))
 }
 
-#[update(name = "transferFrom")]
+#[update(name = "transferFrom", guard = _is_live)]
 #[candid_method(update, rename = "transferFrom")]
-async fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
-    let owner = ic::caller();
-    let from_allowance = allowance(from, owner);
-    let fee = _get_fee();
-    if from_allowance < value.clone() + fee.clone() {
-        return Err(TxError::InsufficientAllowance);
-    }
-    let from_balance = balance_of(from);
-    if from_balance < value.clone() + fee.clone() {
-        return Err(TxError::InsufficientBalance);
-    }
-    _charge_fee(from);
-    _transfer(from, to, value.clone());
-    ALLOWS.with(|a| {
-        let mut allowances = a.borrow_mut();
-        match allowances.get(&from) {
-            Some(inner) => {
-                let result = inner.get(&owner).unwrap().clone();
-                let mut temp = inner.clone();
-                if result.clone() - value.clone() - fee.clone() != 0 {
-                    temp.insert(owner, result - value.clone() - fee.clone());
-                    allowances.insert(from, temp);
-                } else {
-                    temp.remove(&owner);
-                    if temp.len() == 0 {
-                        allowances.remove(&from);
-                    } else {
-                        allowances.insert(from, temp);
-                    }
-                }
-            }
-            None => {
-                assert!(false);
-            }
+fn transfer_from(from: Principal, to: Principal, value: Nat) -> TxReceipt {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.into(),
+        to: to.into(),
+        amount: value,
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let record = match _transfer_from(ic::caller().into(), &args) {
+        Ok(record) => record,
+        Err(TransferFromError::InsufficientAllowance { .. }) => {
+            return Err(TxError::InsufficientAllowance)
         }
-    });
-    _history_inc();
-    add_record(
-        Some(owner),
-        Operation::TransferFrom,
-        from,
-        to,
-        value,
-        fee,
-        ic::time(),
-        TransactionStatus::Succeeded,
-    )
-    .await
-}
-
-#[update(name = "approve")]
-#[candid_method(update)]
-async fn approve(spender: Principal, value: Nat) -> TxReceipt {
-    let owner = ic::caller();
-    let fee = _get_fee();
-    if balance_of(owner) < fee.clone() {
-        return Err(TxError::InsufficientBalance);
-    }
-    _charge_fee(owner);
-    let v = value.clone() + fee.clone();
-    ALLOWS.with(|a| {
-        let mut allowances = a.borrow_mut();
-        match allowances.get(&owner) {
-            Some(inner) => {
-                let mut temp = inner.clone();
-                if v != 0 {
-                    temp.insert(spender, v.clone());
-                    allowances.insert(owner, temp);
-                } else {
-                    temp.remove(&spender);
-                    if temp.len() == 0 {
-                        allowances.remove(&owner);
-                    } else {
-                        allowances.insert(owner, temp);
-                    }
-                }
-            }
-            None => {
-                if v != 0 {
-                    let mut inner = HashMap::new();
-                    inner.insert(spender, v.clone());
-                    allowances.insert(owner, inner);
-                }
-            }
+        Err(TransferFromError::InsufficientFunds { .. }) => {
+            return Err(TxError::InsufficientBalance)
         }
-    });
-    _history_inc();
-    add_record(
-        Some(owner),
-        Operation::Approve,
-        owner,
-        spender,
-        v,
-        fee,
-        ic::time(),
-        TransactionStatus::Succeeded,
-    )
-    .await
-}
-
-#[update(name = "mint")]
-#[candid_method(update, rename = "mint")]
-async fn mint(sub_account: Option<Subaccount>, block_height: BlockHeight) -> TxReceipt {
-    let caller = ic::caller();
-
-    let response: Result<BlockRes, (Option<i32>, String)> =
-        call_with_cleanup(LEDGER_CANISTER_ID, "block_pb", protobuf, block_height).await;
-    let encode_block = match response {
-        Ok(BlockRes(res)) => match res {
-            Some(result_encode_block) => match result_encode_block {
-                Ok(encode_block) => encode_block,
-                Err(e) => {
-                    let storage = match Principal::from_text(e.to_string()) {
-                        Ok(p) => p,
-                        Err(_) => return Err(TxError::Other),
-                    };
-                    let storage_canister = match CanisterId::new(PrincipalId::from(storage)) {
-                        Ok(c) => c,
-                        Err(_) => return Err(TxError::Other),
-                    };
-                    let response: Result<BlockRes, (Option<i32>, String)> =
-                        call_with_cleanup(storage_canister, "get_block_pb", protobuf, block_height)
-                            .await;
-                    match response {
-                        Ok(BlockRes(res)) => match res {
-                            Some(result_encode_block) => match result_encode_block {
-                                Ok(encode_block) => encode_block,
-                                Err(_) => return Err(TxError::Other),
-                            },
-                            None => return Err(TxError::Other),
-                        },
-                        Err(_) => return Err(TxError::Other),
-                    }
-                }
-            },
-            None => return Err(TxError::Other),
-        },
         Err(_) => return Err(TxError::Other),
     };
+    Ok(add_record(record))
+}
 
-    let block = match encode_block.decode() {
-        Ok(block) => block,
-        Err(_) => return Err(TxError::Other),
+#[update(name = "approve", guard = _is_live)]
+#[candid_method(update)]
+fn approve(spender: Principal, value: Nat) -> TxReceipt {
+    // DIP20 spenders expect to move the whole `value`, the fee of the
+    // transferFrom comes on top of it
+    let args = ApproveArgs {
+        from_subaccount: None,
+        spender: spender.into(),
+        amount: value + _get_fee(),
+        expected_allowance: None,
+        expires_at: None,
+        fee: None,
+        memo: None,
+        created_at_time: None,
     };
-
-    let (from, to, amount) = match block.transaction.operation {
-        Operate::Transfer {
-            from,
-            to,
-            amount,
-            fee: _,
-        } => (from, to, amount),
-        _ => {
-            return Err(TxError::ErrorOperationStyle);
-        }
+    let record = match _approve(ic::caller(), &args) {
+        Ok(record) => record,
+        Err(ApproveError::InsufficientFunds { .. }) => return Err(TxError::InsufficientBalance),
+        Err(_) => return Err(TxError::Other),
     };
-    match BLOCKS.with(|b| {
-        let mut blocks = b.borrow_mut();
-        assert_eq!(blocks.insert(block_height), true);
+    Ok(add_record(record))
+}
 
-        let caller_pid = PrincipalId::from(caller);
-        let caller_account = AccountIdentifier::new(caller_pid, sub_account);
+#[update(name = "icrc2_approve", guard = _is_live)]
+#[candid_method(update, rename = "icrc2_approve")]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    let record = _approve(ic::caller(), &args)?;
+    Ok(add_record(record))
+}
 
-        if caller_account != from {
-            blocks.remove(&block_height);
-            return Err(TxError::Unauthorized);
-        }
+#[update(name = "icrc2_transfer_from", guard = _is_live)]
+#[candid_method(update, rename = "icrc2_transfer_from")]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let spender = Account {
+        owner: ic::caller(),
+        subaccount: args.spender_subaccount,
+    };
+    let record = _transfer_from(spender, &args)?;
+    Ok(add_record(record))
+}
 
-        if AccountIdentifier::new(PrincipalId::from(ic::id()), None) != to {
-            blocks.remove(&block_height);
-            return Err(TxError::ErrorTo);
-        }
+#[update(name = "mint", guard = _is_live)]
+#[candid_method(update, rename = "mint")]
+async fn mint(sub_account: Option<Subaccount>, block_height: BlockHeight) -> TxReceipt {
+    let caller = ic::caller();
+    let caller_account =
+        AccountIdentifier::new(&caller, &sub_account.unwrap_or(DEFAULT_SUBACCOUNT));
 
-        if amount < THRESHOLD {
-            blocks.remove(&block_height);
-            return Err(TxError::AmountTooSmall);
-        }
-        return Ok(());
-    }) {
-        Err(err) => return Err(err),
-        _ => {
-            let value = Nat::from(Tokens::get_e8s(amount));
-
-            let user_balance = balance_of(caller);
-            _balance_ins(caller, user_balance + value.clone());
-            _supply_inc(value.clone());
-            _history_inc();
-            add_record(
-                Some(caller),
-                Operation::Mint,
-                caller,
-                caller,
-                value,
-                Nat::from(0),
-                ic::time(),
-                TransactionStatus::Succeeded,
-            )
-            .await
-        }
-    }
+    let transfer = _get_transfer(block_height).await?;
+    _use_block(block_height, &transfer, Some(caller_account))?;
+    let record = _mint_block(caller, caller, block_height, transfer.amount);
+    Ok(add_record(record))
 }
 
-#[update(name = "mintFor")]
+#[update(name = "mintFor", guard = _is_live)]
 #[candid_method(update, rename = "mintFor")]
 async fn mint_for(
     sub_account: Option<Subaccount>,
@@ -425,180 +574,292 @@ async fn mint_for(
     to_p: Principal,
 ) -> TxReceipt {
     let caller = ic::caller();
+    let to_account = AccountIdentifier::new(&to_p, &sub_account.unwrap_or(DEFAULT_SUBACCOUNT));
 
-    let response: Result<BlockRes, (Option<i32>, String)> =
-        call_with_cleanup(LEDGER_CANISTER_ID, "block_pb", protobuf, block_height).await;
-    let encode_block = match response {
-        Ok(BlockRes(res)) => match res {
-            Some(result_encode_block) => match result_encode_block {
-                Ok(encode_block) => encode_block,
-                Err(e) => {
-                    let storage = match Principal::from_text(e.to_string()) {
-                        Ok(p) => p,
-                        Err(_) => return Err(TxError::Other),
-                    };
-                    let storage_canister = match CanisterId::new(PrincipalId::from(storage)) {
-                        Ok(c) => c,
-                        Err(_) => return Err(TxError::Other),
-                    };
-                    let response: Result<BlockRes, (Option<i32>, String)> =
-                        call_with_cleanup(storage_canister, "get_block_pb", protobuf, block_height)
-                            .await;
-                    match response {
-                        Ok(BlockRes(res)) => match res {
-                            Some(result_encode_block) => match result_encode_block {
-                                Ok(encode_block) => encode_block,
-                                Err(_) => return Err(TxError::Other),
-                            },
-                            None => return Err(TxError::Other),
-                        },
-                        Err(_) => return Err(TxError::Other),
-                    }
-                }
-            },
-            None => return Err(TxError::Other),
-        },
-        Err(_) => return Err(TxError::Other),
-    };
+    let transfer = _get_transfer(block_height).await?;
+    _use_block(block_height, &transfer, Some(to_account))?;
+    let record = _mint_block(caller, to_p, block_height, transfer.amount);
+    Ok(add_record(record))
+}
 
-    let block = match encode_block.decode() {
-        Ok(block) => block,
-        Err(_) => return Err(TxError::Other),
-    };
+#[update(name = "mintByMemo", guard = _is_live)]
+#[candid_method(update, rename = "mintByMemo")]
+async fn mint_by_memo(block_height: BlockHeight) -> TxReceipt {
+    let caller = ic::caller();
 
-    let (from, to, amount) = match block.transaction.operation {
-        Operate::Transfer {
-            from,
-            to,
-            amount,
-            fee: _,
-        } => (from, to, amount),
-        _ => {
-            return Err(TxError::ErrorOperationStyle);
-        }
+    let transfer = _get_transfer(block_height).await?;
+    let beneficiary = match transfer.memo.as_deref().and_then(_memo_beneficiary) {
+        Some(beneficiary) => beneficiary,
+        None => return Err(TxError::ErrorTo),
     };
-    match BLOCKS.with(|b| {
-        let mut blocks = b.borrow_mut();
-        assert_eq!(blocks.insert(block_height), true);
-
-        let to_pid = PrincipalId::from(to_p);
-        let to_account = AccountIdentifier::new(to_pid, sub_account);
+    _use_block(block_height, &transfer, None)?;
+    let record = _mint_block(caller, beneficiary, block_height, transfer.amount);
+    Ok(add_record(record))
+}
 
-        if to_account != from {
-            blocks.remove(&block_height);
-            return Err(TxError::Unauthorized);
-        }
+/// The beneficiary named by an ICRC-1 memo: `MINT_MEMO_PREFIX` followed by
+/// the raw bytes of a principal. Any other memo names no one.
+fn _memo_beneficiary(memo: &[u8]) -> Option<Principal> {
+    match memo.strip_prefix(MINT_MEMO_PREFIX) {
+        Some(bytes) if !bytes.is_empty() => Principal::try_from_slice(bytes).ok(),
+        _ => None,
+    }
+}
 
-        if AccountIdentifier::new(PrincipalId::from(ic::id()), None) != to {
-            blocks.remove(&block_height);
-            return Err(TxError::ErrorTo);
-        }
+#[update(name = "mintBatch", guard = _is_live)]
+#[candid_method(update, rename = "mintBatch")]
+async fn mint_batch(
+    sub_account: Option<Subaccount>,
+    block_heights: Vec<BlockHeight>,
+) -> Vec<TxReceipt> {
+    if block_heights.len() > MAX_MINT_BATCH {
+        ic_cdk::api::trap(&format!(
+            "Error: at most {} blocks can be minted at once",
+            MAX_MINT_BATCH
+        ));
+    }
+    let caller = ic::caller();
+    let caller_account =
+        AccountIdentifier::new(&caller, &sub_account.unwrap_or(DEFAULT_SUBACCOUNT));
 
-        if amount < THRESHOLD {
-            blocks.remove(&block_height);
-            return Err(TxError::AmountTooSmall);
-        }
-        return Ok(());
-    }) {
-        Err(err) => return Err(err),
-        _ => {
-            let value = Nat::from(Tokens::get_e8s(amount));
-
-            let user_balance = balance_of(to_p);
-            _balance_ins(to_p, user_balance + value.clone());
-            _supply_inc(value.clone());
-            _history_inc();
-            add_record(
-                Some(caller),
-                Operation::Mint,
-                to_p,
-                to_p,
-                value,
-                Nat::from(0),
-                ic::time(),
-                TransactionStatus::Succeeded,
-            )
-            .await
-        }
+    let mut transfers = Vec::with_capacity(block_heights.len());
+    for chunk in block_heights.chunks(MINT_BATCH_CHUNK) {
+        transfers.extend(join_all(chunk.iter().map(|height| _get_transfer(*height))).await);
     }
+
+    block_heights
+        .iter()
+        .zip(transfers)
+        .map(|(height, transfer)| {
+            let transfer = transfer?;
+            _use_block(*height, &transfer, Some(caller_account))?;
+            let record = _mint_block(caller, caller, *height, transfer.amount);
+            Ok(add_record(record))
+        })
+        .collect()
 }
 
-#[update(name = "withdraw")]
-#[candid_method(update, rename = "withdraw")]
-async fn withdraw(value: u64, to: String) -> TxReceipt {
-    if Tokens::from_e8s(value) < THRESHOLD {
-        return Err(TxError::AmountTooSmall);
-    }
+#[update(name = "notifyDeposit", guard = _is_live)]
+#[candid_method(update, rename = "notifyDeposit")]
+async fn notify_deposit(
+    block_height: BlockHeight,
+    from: Principal,
+    from_subaccount: Option<Subaccount>,
+) -> TxReceipt {
     let caller = ic::caller();
-    let caller_balance = balance_of(caller);
-    let value_nat = Nat::from(value);
-    let total_supply = _supply_get();
-    if caller_balance.clone() < value_nat.clone() || total_supply < value_nat.clone() {
-        return Err(TxError::InsufficientBalance);
+    let from_account =
+        AccountIdentifier::new(&from, &from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT));
+
+    let transfer = _get_transfer(block_height).await?;
+    _use_block(block_height, &transfer, Some(from_account))?;
+    let record = _mint_block(caller, from, block_height, transfer.amount);
+    Ok(add_record(record))
+}
+
+/// Called by the ledger after a `notify_dfx` of a transfer to WICP. The
+/// notification is trusted as it comes from the ledger, so the sender is
+/// credited without fetching the block.
+#[update(name = "transaction_notification", guard = _is_live)]
+#[candid_method(update, rename = "transaction_notification")]
+async fn transaction_notification(notification: TransactionNotification) -> TxReceipt {
+    if ic::caller() != _ledger_id() {
+        return Err(TxError::Unauthorized);
     }
-    let args = SendArgs {
-        memo: Memo(0x57444857),
-        amount: (Tokens::from_e8s(value) - ICPFEE).unwrap(),
-        fee: ICPFEE,
-        from_subaccount: None,
-        to: AccountIdentifier::from_hex(&to).unwrap(),
-        created_at_time: None,
+    let from = notification.from;
+    let transfer = IcpTransfer {
+        from: AccountIdentifier::new(
+            &from,
+            &notification.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
+        ),
+        to: AccountIdentifier::new(
+            &notification.to,
+            &notification.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT),
+        ),
+        amount: notification.amount,
+        memo: None,
     };
-    _balance_ins(caller, caller_balance.clone() - value_nat.clone());
-    _supply_dec(value_nat.clone());
-    let result: Result<(u64,), _> = ic::call(
-        Principal::from(CanisterId::get(LEDGER_CANISTER_ID)),
-        "send_dfx",
-        (args,),
-    )
-    .await;
-    match result {
-        Ok(_) => {
-            _history_inc();
-            add_record(
-                Some(caller),
-                Operation::Burn,
-                caller,
-                caller,
-                value_nat,
-                Nat::from(0),
-                ic::time(),
-                TransactionStatus::Succeeded,
-            )
-            .await
-        }
-        Err(_) => {
-            _balance_ins(caller, balance_of(caller) + value_nat.clone());
-            _supply_inc(value_nat);
-            return Err(TxError::LedgerTrap);
-        }
-    }
+    _use_block(notification.block_height, &transfer, None)?;
+    let record = _mint_block(from, from, notification.block_height, transfer.amount);
+    Ok(add_record(record))
 }
 
-#[query(name = "balanceOf")]
-#[candid_method(query, rename = "balanceOf")]
-fn balance_of(id: Principal) -> Nat {
-    BALANCES.with(|b| {
-        let balances = b.borrow();
-        match balances.get(&id) {
-            Some(balance) => balance.clone(),
-            None => Nat::from(0),
-        }
-    })
+#[update(name = "claimDeposit", guard = _is_live)]
+#[candid_method(update, rename = "claimDeposit")]
+async fn claim_deposit() -> TxReceipt {
+    let caller = ic::caller();
+    // a sweep whose reply was lost is replayed rather than started over
+    if _claim_get(caller).is_none() {
+        let deposit_subaccount = Subaccount::from(&caller);
+        let deposit_account = AccountIdentifier::new(&ic::id(), &deposit_subaccount);
+
+        let balance = match ledger::account_balance(_ledger_id(), deposit_account).await {
+            Ok(balance) => balance,
+            Err(_) => return Err(TxError::LedgerTrap),
+        };
+        let fee = _icp_fee();
+        let amount = match balance.checked_sub(fee) {
+            Some(amount) if amount.e8s() > 0 && amount >= _threshold() => amount,
+            _ => return Err(TxError::AmountTooSmall),
+        };
+
+        // sweep the deposit into the reserve account, only the swept amount is minted
+        let args = TransferArgs {
+            memo: Memo(0),
+            amount,
+            fee,
+            from_subaccount: Some(deposit_subaccount),
+            to: AccountIdentifier::new(&ic::id(), &DEFAULT_SUBACCOUNT),
+            created_at_time: Some(Timestamp {
+                timestamp_nanos: ic::time(),
+            }),
+        };
+        // journal the sweep before sending it, a concurrent call may have
+        // started one meanwhile
+        CLAIMS.with(|c| {
+            c.borrow_mut().claims.entry(caller).or_insert(PendingClaim {
+                args,
+                needs_review: false,
+            });
+        });
+    }
+    let claim = _claim_get(caller).unwrap();
+    if claim.needs_review {
+        return Err(TxError::Other);
+    }
+    let result = ledger::transfer(_ledger_id(), claim.args).await;
+    _claim_settle(caller, result)
 }
 
-#[query(name = "allowance")]
-#[candid_method(query)]
-fn allowance(owner: Principal, spender: Principal) -> Nat {
-    ALLOWS.with(|a| {
-        let allowances = a.borrow();
-        allowances
-            .get(&owner)
-            .unwrap_or(&HashMap::new())
-            .get(&spender)
-            .unwrap_or(&Nat::from(0))
-            .clone()
-    })
+#[update(name = "withdraw", guard = _is_live)]
+#[candid_method(update, rename = "withdraw")]
+async fn withdraw(value: u64, to: String) -> TxReceipt {
+    let to = match AccountIdentifier::from_hex(&to) {
+        Ok(to) => to,
+        Err(_) => return Err(TxError::ErrorTo),
+    };
+    _withdraw(value, to).await
+}
+
+#[update(name = "withdrawTo", guard = _is_live)]
+#[candid_method(update, rename = "withdrawTo")]
+async fn withdraw_to(amount: Nat, to: WithdrawDestination) -> TxReceipt {
+    let value = match u64::try_from(&amount.0) {
+        Ok(value) => value,
+        Err(_) => return Err(TxError::InsufficientBalance),
+    };
+    let to = match to {
+        WithdrawDestination::AccountId(to) => match AccountIdentifier::from_hex(&to) {
+            Ok(to) => to,
+            Err(_) => return Err(TxError::ErrorTo),
+        },
+        WithdrawDestination::Principal(owner, subaccount) => {
+            AccountIdentifier::new(&owner, &subaccount.unwrap_or(DEFAULT_SUBACCOUNT))
+        }
+        WithdrawDestination::Account(account) => AccountIdentifier::from(&account),
+    };
+    _withdraw(value, to).await
+}
+
+#[update(name = "reconcileWithdrawals", guard = _is_reserve_operator)]
+#[candid_method(update, rename = "reconcileWithdrawals")]
+async fn reconcile_withdrawals() -> usize {
+    _reconcile_withdrawals().await
+}
+
+#[update(name = "flushCapQueue", guard = _is_reserve_operator)]
+#[candid_method(update, rename = "flushCapQueue")]
+async fn flush_cap_queue() -> usize {
+    // an explicit flush skips the backoff
+    CAP_STATUS.with(|c| c.borrow_mut().next_retry = 0);
+    _flush_cap_queue().await
+}
+
+#[heartbeat]
+fn heartbeat() {
+    if _restoring() {
+        return;
+    }
+    let now = ic::time();
+    if _cap_flush_due(now) {
+        ic_cdk::block_on(async {
+            _flush_cap_queue().await;
+        });
+    }
+    // a paused canister, e.g. one being exported, doesn't move funds
+    let due = LAST_RECONCILE.with(|l| now - *l.borrow() >= RECONCILE_INTERVAL);
+    if due && !is_paused() {
+        LAST_RECONCILE.with(|l| *l.borrow_mut() = now);
+        ic_cdk::block_on(async {
+            if _ledger_config().sync_fee {
+                let _ = _sync_icp_fee().await;
+            }
+            _reconcile_withdrawals().await;
+            _reconcile_claims().await;
+            let _ = _reserve_status(true).await;
+        });
+    }
+}
+
+#[update(name = "getReserveStatus")]
+#[candid_method(update, rename = "getReserveStatus")]
+async fn get_reserve_status() -> Result<ReserveStatus, TxError> {
+    // only trusted callers add to the bounded history, anyone else just reads
+    let record = _has_role(&ic::caller(), Role::ReserveOperator);
+    _reserve_status(record).await
+}
+
+async fn _reserve_status(record: bool) -> Result<ReserveStatus, TxError> {
+    let reserve_account = AccountIdentifier::new(&ic::id(), &DEFAULT_SUBACCOUNT);
+    let reserve = match ledger::account_balance(_ledger_id(), reserve_account).await {
+        Ok(balance) => Nat::from(balance.e8s()),
+        Err(_) => return Err(TxError::LedgerTrap),
+    };
+    let total_supply = _supply_get();
+    // in-flight payouts are already burned but may still sit in the reserve
+    let pending_withdrawals = WITHDRAWALS.with(|w| w.borrow().pending());
+    let (surplus, deficit) = if reserve >= total_supply {
+        (reserve.clone() - total_supply.clone(), Nat::from(0))
+    } else {
+        (Nat::from(0), total_supply.clone() - reserve.clone())
+    };
+    RESERVE.with(|r| {
+        let mut reserve_data = r.borrow_mut();
+        let status = ReserveStatus {
+            total_supply,
+            reserve,
+            surplus,
+            deficit,
+            pending_withdrawals,
+            fees_collected: reserve_data.fees_collected.clone(),
+            icp_fees_spent: reserve_data.icp_fees_spent.clone(),
+            timestamp: ic::time(),
+        };
+        if record {
+            if reserve_data.history.len() == RESERVE_HISTORY_SIZE {
+                reserve_data.history.pop_front();
+            }
+            reserve_data.history.push_back(status.clone());
+        }
+        Ok(status)
+    })
+}
+
+#[query(name = "balanceOf")]
+#[candid_method(query, rename = "balanceOf")]
+fn balance_of(id: Principal) -> Nat {
+    _balance_get(id)
+}
+
+#[query(name = "allowance")]
+#[candid_method(query)]
+fn allowance(owner: Principal, spender: Principal) -> Nat {
+    _allowance_get(owner, spender).allowance
+}
+
+#[query(name = "icrc2_allowance")]
+#[candid_method(query, rename = "icrc2_allowance")]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    _allowance_get(args.account, args.spender)
 }
 
 #[query]
@@ -655,6 +916,89 @@ fn owner() -> Principal {
     })
 }
 
+#[query(name = "icrc1_name")]
+#[candid_method(query, rename = "icrc1_name")]
+fn icrc1_name() -> String {
+    name()
+}
+
+#[query(name = "icrc1_symbol")]
+#[candid_method(query, rename = "icrc1_symbol")]
+fn icrc1_symbol() -> String {
+    symbol()
+}
+
+#[query(name = "icrc1_decimals")]
+#[candid_method(query, rename = "icrc1_decimals")]
+fn icrc1_decimals() -> u8 {
+    decimals()
+}
+
+#[query(name = "icrc1_fee")]
+#[candid_method(query, rename = "icrc1_fee")]
+fn icrc1_fee() -> Nat {
+    _get_fee()
+}
+
+#[query(name = "icrc1_total_supply")]
+#[candid_method(query, rename = "icrc1_total_supply")]
+fn icrc1_total_supply() -> Nat {
+    total_supply()
+}
+
+/// WICP is only minted against ICP deposits, so there is no minting account.
+#[query(name = "icrc1_minting_account")]
+#[candid_method(query, rename = "icrc1_minting_account")]
+fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+#[query(name = "icrc1_balance_of")]
+#[candid_method(query, rename = "icrc1_balance_of")]
+fn icrc1_balance_of(account: Account) -> Nat {
+    _balance_get(account)
+}
+
+#[query(name = "icrc1_metadata")]
+#[candid_method(query, rename = "icrc1_metadata")]
+fn icrc1_metadata() -> Vec<(String, Value)> {
+    STATS.with(|s| {
+        let stats = s.borrow();
+        vec![
+            ("icrc1:name".to_string(), Value::Text(stats.name.clone())),
+            (
+                "icrc1:symbol".to_string(),
+                Value::Text(stats.symbol.clone()),
+            ),
+            (
+                "icrc1:decimals".to_string(),
+                Value::Nat(Nat::from(stats.decimals)),
+            ),
+            ("icrc1:fee".to_string(), Value::Nat(stats.fee.clone())),
+            ("icrc1:logo".to_string(), Value::Text(stats.logo.clone())),
+        ]
+    })
+}
+
+#[query(name = "icrc1_supported_standards")]
+#[candid_method(query, rename = "icrc1_supported_standards")]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+        StandardRecord {
+            name: "DIP-20".to_string(),
+            url: "https://github.com/Psychedelic/DIP20".to_string(),
+        },
+    ]
+}
+
 #[query(name = "getMetadata")]
 #[candid_method(query, rename = "getMetadata")]
 fn get_metadata() -> Metadata {
@@ -681,34 +1025,103 @@ fn history_size() -> usize {
     })
 }
 
+#[query(name = "getTransaction")]
+#[candid_method(query, rename = "getTransaction")]
+fn get_transaction(index: usize) -> TxRecord {
+    HISTORY.with(|h| match h.borrow().get(index) {
+        Some(record) => record,
+        None => ic_cdk::api::trap("Error: transaction not found"),
+    })
+}
+
+#[query(name = "getTransactions")]
+#[candid_method(query, rename = "getTransactions")]
+fn get_transactions(start: usize, limit: usize) -> Vec<TxRecord> {
+    HISTORY.with(|h| h.borrow().range(start, limit))
+}
+
+#[query(name = "getCapQueueStatus")]
+#[candid_method(query, rename = "getCapQueueStatus")]
+fn get_cap_queue_status() -> CapQueueStatus {
+    let mut status = CAP_STATUS.with(|c| c.borrow().clone());
+    status.queued = TXLOG.with(|t| t.borrow().len());
+    status
+}
+
+#[query(name = "getUserTransactions")]
+#[candid_method(query, rename = "getUserTransactions")]
+fn get_user_transactions(who: Principal, start: usize, limit: usize) -> Vec<TxRecord> {
+    get_user_transactions_filtered(who, TxFilter::default(), start, limit)
+}
+
+#[query(name = "getUserTransactionsFiltered")]
+#[candid_method(query, rename = "getUserTransactionsFiltered")]
+fn get_user_transactions_filtered(
+    who: Principal,
+    filter: TxFilter,
+    start: usize,
+    limit: usize,
+) -> Vec<TxRecord> {
+    HISTORY.with(|h| {
+        let history = h.borrow();
+        history
+            .user_txs(who)
+            .filter_map(|index| history.get(index))
+            .filter(|record| filter.matches(record))
+            .skip(start)
+            .take(limit)
+            .collect()
+    })
+}
+
+#[query(name = "getUserTransactionAmount")]
+#[candid_method(query, rename = "getUserTransactionAmount")]
+fn get_user_transaction_amount(who: Principal) -> usize {
+    HISTORY.with(|h| h.borrow().user_txs(who).count())
+}
+
 #[query(name = "getTokenInfo")]
 #[candid_method(query, rename = "getTokenInfo")]
 fn get_token_info() -> TokenInfo {
     STATS.with(|s| {
         let stats = s.borrow();
         BALANCES.with(|b| {
-            let balance = b.borrow();
+            // principals, like getHolders, not their subaccounts
+            let holders: HashSet<Principal> = b
+                .borrow()
+                .iter()
+                .map(|(AccountKey(owner, _), _)| owner)
+                .collect();
             TokenInfo {
                 metadata: get_metadata(),
                 feeTo: stats.fee_to,
                 historySize: stats.history_size,
                 deployTime: stats.deploy_time,
-                holderNumber: balance.len(),
+                holderNumber: holders.len(),
                 cycles: ic::balance(),
             }
         })
     })
 }
 
+/// Version of the stable state layout, see `upgrade.rs`.
+#[query(name = "getSchemaVersion")]
+#[candid_method(query, rename = "getSchemaVersion")]
+fn get_schema_version() -> u32 {
+    upgrade::SCHEMA_VERSION
+}
+
 #[query(name = "getHolders")]
 #[candid_method(query, rename = "getHolders")]
 fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
     BALANCES.with(|b| {
         let balances = b.borrow();
-        let mut bal = Vec::new();
-        for (k, v) in balances.clone() {
-            bal.push((k, v.clone()));
+        // DIP20 holders are principals, their subaccounts are summed up
+        let mut holders: HashMap<Principal, Nat> = HashMap::new();
+        for (AccountKey(owner, _), v) in balances.iter() {
+            *holders.entry(owner).or_insert_with(|| Nat::from(0)) += v.0;
         }
+        let mut bal = Vec::from_iter(holders.into_iter());
         bal.sort_by(|a, b| b.1.cmp(&a.1));
         let limit: usize = if start + limit > bal.len() {
             bal.len() - start
@@ -722,43 +1135,157 @@ fn get_holders(start: usize, limit: usize) -> Vec<(Principal, Nat)> {
 #[query(name = "getAllowanceSize")]
 #[candid_method(query, rename = "getAllowanceSize")]
 fn get_allowance_size() -> usize {
-    ALLOWS.with(|a| {
-        let allowances = a.borrow();
-        let mut size = 0;
-        for (_, v) in allowances.iter() {
-            size += v.len();
-        }
-        size
-    })
+    ALLOWS.with(|a| a.borrow().len() as usize)
 }
 
 #[query(name = "getUserApprovals")]
 #[candid_method(query, rename = "getUserApprovals")]
 fn get_user_approvals(who: Principal) -> Vec<(Principal, Nat)> {
+    let now = ic::time();
+    let owner = Account::from(who).key();
     ALLOWS.with(|a| {
-        let allowances = a.borrow();
-        match allowances.get(&who) {
-            Some(allow) => return Vec::from_iter(allow.clone().into_iter()),
-            None => return Vec::new(),
-        }
+        a.borrow()
+            .range(AllowanceKey::first_of(owner)..)
+            .take_while(|(key, _)| key.owner == owner)
+            .filter(|(_, allowance)| !allowance.is_expired(now))
+            .map(|(key, allowance)| (key.spender.0, allowance.allowance))
+            .collect()
     })
 }
 
 #[query(name = "getBlockUsed")]
 #[candid_method(query, rename = "getBlockUsed")]
-fn get_block_used() -> HashSet<u64> {
-    BLOCKS.with(|b| b.borrow().clone())
+fn get_block_used(start: usize, limit: usize) -> Vec<BlockHeight> {
+    BLOCKS.with(|b| b.borrow().iter().skip(start).take(limit).collect())
+}
+
+#[query(name = "getBlockUsedSize")]
+#[candid_method(query, rename = "getBlockUsedSize")]
+fn get_block_used_size() -> usize {
+    BLOCKS.with(|b| b.borrow().len())
+}
+
+#[query(name = "getBlockWatermark")]
+#[candid_method(query, rename = "getBlockWatermark")]
+fn get_block_watermark() -> BlockHeight {
+    BLOCKS.with(|b| b.borrow().watermark())
 }
 
 #[query(name = "isBlockUsed")]
 #[candid_method(query, rename = "isBlockUsed")]
 fn is_block_used(block_number: BlockHeight) -> bool {
-    BLOCKS.with(|b| b.borrow().clone().contains(&block_number))
+    BLOCKS.with(|b| b.borrow().contains(block_number))
+}
+
+#[query(name = "getBlockTransaction")]
+#[candid_method(query, rename = "getBlockTransaction")]
+fn get_block_transaction(block_number: BlockHeight) -> Option<Nat> {
+    BLOCKS.with(|b| b.borrow().minted(block_number))
+}
+
+#[query(name = "getDepositAccount")]
+#[candid_method(query, rename = "getDepositAccount")]
+fn get_deposit_account(who: Principal) -> String {
+    AccountIdentifier::new(&ic::id(), &Subaccount::from(&who)).to_hex()
+}
+
+#[query(name = "getWithdrawal")]
+#[candid_method(query, rename = "getWithdrawal")]
+fn get_withdrawal(id: u64) -> Option<Withdrawal> {
+    WITHDRAWALS.with(|w| w.borrow().get(id))
+}
+
+#[query(name = "getPendingClaim")]
+#[candid_method(query, rename = "getPendingClaim")]
+fn get_pending_claim(who: Principal) -> Option<PendingClaim> {
+    _claim_get(who)
+}
+
+/// Settles a sweep the ledger can no longer deduplicate, once its outcome was
+/// looked up on the ledger: `block` is the sweep's block if it went through,
+/// which is then minted, and `None` drops the claim.
+#[update(name = "resolveClaim", guard = _is_reserve_operator)]
+#[candid_method(update, rename = "resolveClaim")]
+fn resolve_claim(who: Principal, block: Option<BlockHeight>) -> Result<(), String> {
+    match _claim_get(who) {
+        Some(claim) if claim.needs_review => {}
+        _ => return Err("Error: no claim waiting for review".to_string()),
+    }
+    match block {
+        Some(block_height) => match _claim_settle(who, Ok(Ok(block_height))) {
+            Ok(_) => Ok(()),
+            Err(_) => Err("Error: block already used".to_string()),
+        },
+        None => {
+            _claim_rem(who);
+            Ok(())
+        }
+    }
+}
+
+/// Settles a withdrawal waiting for review, with the block that paid it out
+/// or, when it was never paid, by crediting the burned amount back.
+#[update(name = "resolveWithdrawal", guard = _is_reserve_operator)]
+#[candid_method(update, rename = "resolveWithdrawal")]
+fn resolve_withdrawal(id: u64, block: Option<BlockIndex>) -> Result<(), String> {
+    let mut withdrawal = match WITHDRAWALS.with(|w| w.borrow().get(id)) {
+        Some(withdrawal) if withdrawal.status == WithdrawalStatus::NeedsReview => withdrawal,
+        _ => return Err("Error: no withdrawal waiting for review".to_string()),
+    };
+    match (block, withdrawal.block_index) {
+        // never answered, settled like a ledger reply
+        (Some(block_index), None) => {
+            withdrawal.status = WithdrawalStatus::Pending;
+            _withdrawal_set(withdrawal);
+            if let Some(withdrawal) = _withdrawal_settle(id, Ok(Ok(block_index))) {
+                _record_withdrawal(withdrawal);
+            }
+        }
+        // the burn is already recorded, only the block to confirm changes
+        (Some(block_index), Some(_)) => {
+            withdrawal.status = WithdrawalStatus::Sent;
+            withdrawal.block_index = Some(block_index);
+            _withdrawal_set(withdrawal);
+        }
+        (None, None) => {
+            _withdrawal_refund(&mut withdrawal);
+            _withdrawal_set(withdrawal);
+        }
+        (None, Some(_)) => return Err("Error: the payout was sent, give its block".to_string()),
+    }
+    Ok(())
+}
+
+#[query(name = "getUserWithdrawals")]
+#[candid_method(query, rename = "getUserWithdrawals")]
+fn get_user_withdrawals(who: Principal) -> Vec<Withdrawal> {
+    WITHDRAWALS.with(|w| w.borrow().of(who).collect())
+}
+
+#[query(name = "getReserveHistory")]
+#[candid_method(query, rename = "getReserveHistory")]
+fn get_reserve_history(start: usize, limit: usize) -> Vec<ReserveStatus> {
+    RESERVE.with(|r| {
+        let reserve_data = r.borrow();
+        reserve_data
+            .history
+            .iter()
+            .skip(start)
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+#[query(name = "getLedgerConfig")]
+#[candid_method(query, rename = "getLedgerConfig")]
+fn get_ledger_config() -> LedgerConfig {
+    _ledger_config()
 }
 
 /* PERMISSIONED FNS */
 
-#[update(name = "setName", guard = _is_auth)]
+#[update(name = "setName", guard = _is_metadata_admin)]
 #[candid_method(update, rename = "setName")]
 fn set_name(name: String) {
     STATS.with(|s| {
@@ -767,7 +1294,7 @@ fn set_name(name: String) {
     });
 }
 
-#[update(name = "setLogo", guard = _is_auth)]
+#[update(name = "setLogo", guard = _is_metadata_admin)]
 #[candid_method(update, rename = "setLogo")]
 fn set_logo(logo: String) {
     STATS.with(|s| {
@@ -776,7 +1303,7 @@ fn set_logo(logo: String) {
     });
 }
 
-#[update(name = "setFee", guard = _is_auth)]
+#[update(name = "setFee", guard = _is_fee_admin)]
 #[candid_method(update, rename = "setFee")]
 fn set_fee(fee: Nat) {
     STATS.with(|s| {
@@ -785,7 +1312,7 @@ fn set_fee(fee: Nat) {
     });
 }
 
-#[update(name = "setFeeTo", guard = _is_auth)]
+#[update(name = "setFeeTo", guard = _is_fee_admin)]
 #[candid_method(update, rename = "setFeeTo")]
 fn set_fee_to(fee_to: Principal) {
     STATS.with(|s| {
@@ -794,66 +1321,246 @@ fn set_fee_to(fee_to: Principal) {
     });
 }
 
-#[update(name = "setOwner", guard = _is_auth)]
+#[update(name = "setOwner")]
 #[candid_method(update, rename = "setOwner")]
-fn set_owner(owner: Principal) {
+async fn set_owner(owner: Principal) {
+    if !_is_super_admin(ic::caller()).await {
+        ic_cdk::api::trap("Error: Unauthorized principal ID");
+    }
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.owner = owner;
     });
 }
 
-#[update(name = "setGenesis", guard = _is_auth)]
-#[candid_method(update, rename = "setGenesis")]
-async fn set_genesis() -> TxReceipt {
-    let mut genesis = Genesis::default();
-    GENESIS.with(|g| {
-        genesis = g.borrow().clone();
+/// Sets the ICP fee, the threshold and fee syncing. The ledger is changed
+/// with `setLedgerId`.
+#[update(name = "setLedgerConfig", guard = _is_fee_admin)]
+#[candid_method(update, rename = "setLedgerConfig")]
+fn set_ledger_config(ledger_config: LedgerConfig) {
+    if ledger_config.ledger_id != _ledger_id() {
+        ic_cdk::api::trap("Error: the ledger can only be changed with setLedgerId");
+    }
+    CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        *config = ledger_config;
     });
-    add_record(
-        genesis.caller,
-        genesis.op,
-        genesis.from,
-        genesis.to,
-        genesis.amount.clone(),
-        genesis.fee.clone(),
-        genesis.timestamp,
-        genesis.status,
-    )
-    .await
+}
+
+/// Points the canister at another ledger, which decides what every mint and
+/// withdrawal is backed by, so only the owner or a controller may.
+#[update(name = "setLedgerId")]
+#[candid_method(update, rename = "setLedgerId")]
+async fn set_ledger_id(ledger_id: Principal) -> Result<(), String> {
+    if !_is_super_admin(ic::caller()).await {
+        return Err("Error: Unauthorized principal ID".to_string());
+    }
+    CONFIG.with(|c| c.borrow_mut().ledger_id = ledger_id);
+    Ok(())
+}
+
+#[update(name = "syncIcpFee", guard = _is_fee_admin)]
+#[candid_method(update, rename = "syncIcpFee")]
+async fn sync_icp_fee() -> Result<Tokens, TxError> {
+    _sync_icp_fee().await
+}
+
+#[update(name = "setBlockWatermark", guard = _is_reserve_operator)]
+#[candid_method(update, rename = "setBlockWatermark")]
+fn set_block_watermark(watermark: BlockHeight) {
+    BLOCKS.with(|b| b.borrow_mut().set_watermark(watermark));
+}
+
+#[update(name = "setGenesis", guard = _is_upgrader)]
+#[candid_method(update, rename = "setGenesis")]
+fn set_genesis() -> TxReceipt {
+    let genesis = GENESIS.with(|g| g.borrow().clone());
+    let record = match genesis.record {
+        Some(record) => record,
+        None => return Err(TxError::Other),
+    };
+    // the genesis is only ever sent once, calling again is a no-op
+    if genesis.recorded {
+        return Ok(record.index);
+    }
+    if !_has_backend(HistoryBackend::Cap) {
+        return Err(TxError::Other);
+    }
+    CapSink.record(&record);
+    GENESIS.with(|g| g.borrow_mut().recorded = true);
+    Ok(record.index)
+}
+
+#[query(name = "getGenesis")]
+#[candid_method(query, rename = "getGenesis")]
+fn get_genesis() -> Genesis {
+    GENESIS.with(|g| g.borrow().clone())
+}
+
+/// Pages through the state. The canister must stay paused for the whole
+/// export, so that the pages add up to a consistent snapshot.
+#[query(name = "exportState", guard = _is_upgrader)]
+#[candid_method(query, rename = "exportState")]
+fn export_state(cursor: Option<ExportCursor>) -> Result<StateChunk, String> {
+    if !PAUSED.with(|p| *p.borrow()) {
+        return Err("Error: pause the canister before exporting its state".to_string());
+    }
+    Ok(backup::export(cursor))
+}
+
+/// Puts a fresh canister in restoring mode, where transfers, mints and
+/// withdrawals are rejected until `finishRestore`.
+#[update(name = "beginRestore", guard = _is_upgrader)]
+#[candid_method(update, rename = "beginRestore")]
+fn begin_restore() -> Result<(), String> {
+    // nothing but the genesis
+    if STATS.with(|s| s.borrow().history_size) > 1 {
+        return Err("Error: only a fresh canister can be restored".to_string());
+    }
+    backup::begin();
+    Ok(())
+}
+
+#[update(name = "importState", guard = _is_upgrader)]
+#[candid_method(update, rename = "importState")]
+fn import_state(chunk: StateChunk) -> Result<(), String> {
+    backup::import(chunk)
+}
+
+#[update(name = "finishRestore", guard = _is_upgrader)]
+#[candid_method(update, rename = "finishRestore")]
+fn finish_restore() -> Result<(), String> {
+    backup::finish()
+}
+
+/// Stops transfers, approvals, mints and withdrawals until unpaused.
+#[update(name = "setPaused", guard = _is_pauser)]
+#[candid_method(update, rename = "setPaused")]
+fn set_paused(paused: bool) {
+    PAUSED.with(|p| *p.borrow_mut() = paused);
+}
+
+#[query(name = "isPaused")]
+#[candid_method(query, rename = "isPaused")]
+fn is_paused() -> bool {
+    PAUSED.with(|p| *p.borrow())
+}
+
+#[update(name = "grantRole")]
+#[candid_method(update, rename = "grantRole")]
+async fn grant_role(who: Principal, role: Role) -> Result<(), String> {
+    if !_is_super_admin(ic::caller()).await {
+        return Err("Error: Unauthorized principal ID".to_string());
+    }
+    ROLES.with(|r| r.borrow_mut().grant(who, role));
+    Ok(())
+}
+
+#[update(name = "revokeRole")]
+#[candid_method(update, rename = "revokeRole")]
+async fn revoke_role(who: Principal, role: Role) -> Result<(), String> {
+    if !_is_super_admin(ic::caller()).await {
+        return Err("Error: Unauthorized principal ID".to_string());
+    }
+    ROLES.with(|r| r.borrow_mut().revoke(who, role));
+    Ok(())
+}
+
+/// Roles granted so far, the owner isn't listed but holds them all.
+#[query(name = "getRoles")]
+#[candid_method(query, rename = "getRoles")]
+fn get_roles() -> Vec<(Principal, Vec<Role>)> {
+    ROLES.with(|r| r.borrow().iter().collect())
+}
+
+#[query(name = "hasRole")]
+#[candid_method(query, rename = "hasRole")]
+fn has_role(who: Principal, role: Role) -> bool {
+    _has_role(&who, role)
 }
 
 /* INTERNAL FNS */
 
-// TODO: use controllers for ownership
-// this will require the canister to be a controller of itself (like dip721)
-fn _is_auth() -> Result<(), String> {
-    STATS.with(|s| {
-        let stats = s.borrow();
-        if ic_cdk::api::caller() == stats.owner {
-            Ok(())
-        } else {
-            Err("Error: Unauthorized principal ID".to_string())
+/// The owner is the super-admin and holds every role.
+fn _has_role(who: &Principal, role: Role) -> bool {
+    STATS.with(|s| s.borrow().owner == *who) || ROLES.with(|r| r.borrow().has(who, role))
+}
+
+/// The owner and the canister controllers can change the owner and the roles.
+async fn _is_super_admin(who: Principal) -> bool {
+    STATS.with(|s| s.borrow().owner == who) || auth::is_controller(who).await
+}
+
+fn _require(role: Role) -> Result<(), String> {
+    if _has_role(&ic_cdk::api::caller(), role) {
+        Ok(())
+    } else {
+        Err("Error: Unauthorized principal ID".to_string())
+    }
+}
+
+fn _is_metadata_admin() -> Result<(), String> {
+    _require(Role::MetadataAdmin)
+}
+
+fn _is_fee_admin() -> Result<(), String> {
+    _require(Role::FeeAdmin)
+}
+
+fn _is_pauser() -> Result<(), String> {
+    _require(Role::Pauser)
+}
+
+fn _is_reserve_operator() -> Result<(), String> {
+    _require(Role::ReserveOperator)
+}
+
+fn _is_upgrader() -> Result<(), String> {
+    _require(Role::Upgrader)
+}
+
+fn _restoring() -> bool {
+    RESTORING.with(|r| r.borrow().is_some())
+}
+
+fn _is_live() -> Result<(), String> {
+    if _restoring() {
+        Err("Error: the canister is being restored".to_string())
+    } else if is_paused() {
+        Err("Error: the canister is paused".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+fn _balance_get(account: impl Into<Account>) -> Nat {
+    BALANCES.with(|b| {
+        let balances = b.borrow();
+        match balances.get(&account.into().key()) {
+            Some(balance) => balance.0,
+            None => Nat::from(0),
         }
     })
 }
 
-fn _balance_ins(from: Principal, value: Nat) {
+fn _balance_ins(from: impl Into<Account>, value: Nat) {
     BALANCES.with(|b| {
         let mut balances = b.borrow_mut();
-        balances.insert(from, value);
+        balances.insert(from.into().key(), StableNat(value));
     });
 }
 
-fn _balance_rem(from: Principal) {
+fn _balance_rem(from: impl Into<Account>) {
     BALANCES.with(|b| {
         let mut balances = b.borrow_mut();
-        balances.remove(&from);
+        balances.remove(&from.into().key());
     });
 }
 
-fn _transfer(from: Principal, to: Principal, value: Nat) {
-    let from_balance = balance_of(from);
+fn _transfer(from: impl Into<Account>, to: impl Into<Account>, value: Nat) {
+    let from = from.into();
+    let to = to.into();
+    let from_balance = _balance_get(from);
     let from_balance_new = from_balance - value.clone();
 
     // TODO: check this logic ↴
@@ -862,13 +1569,230 @@ fn _transfer(from: Principal, to: Principal, value: Nat) {
     } else {
         _balance_rem(from)
     }
-    let to_balance = balance_of(to);
+    let to_balance = _balance_get(to);
     let to_balance_new = to_balance + value;
     if to_balance_new != 0 {
         _balance_ins(to, to_balance_new);
     }
 }
 
+/// Current allowance of `spender` over `owner`, expired ones read as zero.
+fn _allowance_get(owner: impl Into<Account>, spender: impl Into<Account>) -> Allowance {
+    let now = ic::time();
+    let key = AllowanceKey {
+        owner: owner.into().key(),
+        spender: spender.into().key(),
+    };
+    ALLOWS.with(|a| {
+        a.borrow()
+            .get(&key)
+            .filter(|allowance| !allowance.is_expired(now))
+            .unwrap_or_default()
+    })
+}
+
+/// Stores the allowance, dropping it when empty. Expired allowances of the
+/// owner are pruned on the way.
+fn _allowance_set(owner: Account, spender: Account, allowance: Allowance) {
+    let now = ic::time();
+    let owner = owner.key();
+    let key = AllowanceKey {
+        owner,
+        spender: spender.key(),
+    };
+    ALLOWS.with(|a| {
+        let mut allowances = a.borrow_mut();
+        let expired: Vec<_> = allowances
+            .range(AllowanceKey::first_of(owner)..)
+            .take_while(|(key, _)| key.owner == owner)
+            .filter(|(_, allowance)| allowance.is_expired(now))
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            allowances.remove(&key);
+        }
+        if allowance.allowance == 0 {
+            allowances.remove(&key);
+        } else {
+            allowances.insert(key, allowance);
+        }
+    });
+}
+
+/// Rejects a request outside the dedup window or already applied. Requests
+/// without `created_at_time` aren't deduplicated.
+fn _dedup_check<T: CandidType>(
+    created_at_time: Option<u64>,
+    request: T,
+) -> Result<Option<(u64, TxHash)>, DedupError> {
+    let created_at = match created_at_time {
+        Some(created_at) => created_at,
+        None => return Ok(None),
+    };
+    let hash = tx_hash(request);
+    DEDUP.with(|d| d.borrow().check(created_at, hash, ic::time()))?;
+    Ok(Some((created_at, hash)))
+}
+
+fn _dedup_ins(key: Option<(u64, TxHash)>, tx_id: &Nat) {
+    if let Some((created_at, hash)) = key {
+        DEDUP.with(|d| {
+            let mut dedup = d.borrow_mut();
+            dedup.prune(ic::time());
+            dedup.insert(created_at, hash, tx_id.clone());
+        });
+    }
+}
+
+fn _memo_too_long(memo: &Option<Vec<u8>>) -> bool {
+    memo.as_ref()
+        .map_or(false, |memo| memo.len() > MAX_MEMO_SIZE)
+}
+
+/// Checks and applies an ICRC-1 transfer from `from`, returns its record.
+fn _icrc1_transfer(from: Account, arg: &TransferArg) -> Result<TxRecord, icrc1::TransferError> {
+    if _memo_too_long(&arg.memo) {
+        return Err(icrc1::TransferError::GenericError {
+            error_code: Nat::from(1),
+            message: "Error: memo is too long".to_string(),
+        });
+    }
+    let fee = _get_fee();
+    if arg.fee.as_ref().map_or(false, |expected| *expected != fee) {
+        return Err(icrc1::TransferError::BadFee { expected_fee: fee });
+    }
+    let dedup = _dedup_check(arg.created_at_time, ("transfer", from, arg.clone()))?;
+    let balance = _balance_get(from);
+    if balance < arg.amount.clone() + fee.clone() {
+        return Err(icrc1::TransferError::InsufficientFunds { balance });
+    }
+    _charge_fee(from);
+    _transfer(from, arg.to, arg.amount.clone());
+    let record = _record(
+        Some(from.owner),
+        Operation::Transfer,
+        from.owner,
+        arg.to.owner,
+        arg.amount.clone(),
+        fee,
+        TransactionStatus::Succeeded,
+    );
+    _dedup_ins(dedup, &record.index);
+    Ok(record)
+}
+
+/// Checks and applies an ICRC-2 approval from `owner`, returns its record.
+fn _approve(owner: Principal, args: &ApproveArgs) -> Result<TxRecord, ApproveError> {
+    let from = Account {
+        owner,
+        subaccount: args.from_subaccount,
+    };
+    if _memo_too_long(&args.memo) {
+        return Err(ApproveError::GenericError {
+            error_code: Nat::from(1),
+            message: "Error: memo is too long".to_string(),
+        });
+    }
+    let now = ic::time();
+    if args
+        .expires_at
+        .map_or(false, |expires_at| expires_at <= now)
+    {
+        return Err(ApproveError::Expired { ledger_time: now });
+    }
+    let fee = _get_fee();
+    if args.fee.as_ref().map_or(false, |expected| *expected != fee) {
+        return Err(ApproveError::BadFee { expected_fee: fee });
+    }
+    let dedup = _dedup_check(args.created_at_time, ("approve", from, args.clone()))?;
+    let current = _allowance_get(from, args.spender);
+    if let Some(expected) = &args.expected_allowance {
+        if *expected != current.allowance {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: current.allowance,
+            });
+        }
+    }
+    let balance = _balance_get(from);
+    if balance < fee {
+        return Err(ApproveError::InsufficientFunds { balance });
+    }
+    _charge_fee(from);
+    _allowance_set(
+        from,
+        args.spender,
+        Allowance {
+            allowance: args.amount.clone(),
+            expires_at: args.expires_at,
+        },
+    );
+    let record = _record(
+        Some(owner),
+        Operation::Approve,
+        owner,
+        args.spender.owner,
+        args.amount.clone(),
+        fee,
+        TransactionStatus::Succeeded,
+    );
+    _dedup_ins(dedup, &record.index);
+    Ok(record)
+}
+
+/// Checks and applies an ICRC-2 transfer by `spender`, returns its record.
+/// The allowance has to cover both the amount and the fee.
+fn _transfer_from(
+    spender: Account,
+    args: &TransferFromArgs,
+) -> Result<TxRecord, TransferFromError> {
+    if _memo_too_long(&args.memo) {
+        return Err(TransferFromError::GenericError {
+            error_code: Nat::from(1),
+            message: "Error: memo is too long".to_string(),
+        });
+    }
+    let fee = _get_fee();
+    if args.fee.as_ref().map_or(false, |expected| *expected != fee) {
+        return Err(TransferFromError::BadFee { expected_fee: fee });
+    }
+    let dedup = _dedup_check(
+        args.created_at_time,
+        ("transfer_from", spender, args.clone()),
+    )?;
+    let total = args.amount.clone() + fee.clone();
+    let allowance = _allowance_get(args.from, spender);
+    if allowance.allowance < total {
+        return Err(TransferFromError::InsufficientAllowance {
+            allowance: allowance.allowance,
+        });
+    }
+    let balance = _balance_get(args.from);
+    if balance < total {
+        return Err(TransferFromError::InsufficientFunds { balance });
+    }
+    _charge_fee(args.from);
+    _transfer(args.from, args.to, args.amount.clone());
+    _allowance_set(
+        args.from,
+        spender,
+        Allowance {
+            allowance: allowance.allowance - total,
+            expires_at: allowance.expires_at,
+        },
+    );
+    let record = _record(
+        Some(spender.owner),
+        Operation::TransferFrom,
+        args.from.owner,
+        args.to.owner,
+        args.amount.clone(),
+        fee,
+        TransactionStatus::Succeeded,
+    );
+    _dedup_ins(dedup, &record.index);
+    Ok(record)
+}
+
 fn _supply_inc(value: Nat) {
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
@@ -890,22 +1814,53 @@ fn _supply_get() -> Nat {
     })
 }
 
-fn _history_inc() {
+/// Bumps the history size and returns the index of the new entry.
+fn _history_inc() -> usize {
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         stats.history_size += 1;
+        stats.history_size - 1
     })
 }
 
-fn _charge_fee(user: Principal) {
+/// Builds the record of a committed transaction under the next history index.
+fn _record(
+    caller: Option<Principal>,
+    op: Operation,
+    from: Principal,
+    to: Principal,
+    amount: Nat,
+    fee: Nat,
+    status: TransactionStatus,
+) -> TxRecord {
+    let index = _history_inc();
+    TxRecord {
+        caller,
+        index: Nat::from(index),
+        from,
+        to,
+        amount,
+        fee,
+        timestamp: Int::from(ic::time()),
+        status,
+        operation: op,
+    }
+}
+
+fn _charge_fee(user: impl Into<Account>) {
     STATS.with(|s| {
         let stats = s.borrow();
         if stats.fee > Nat::from(0) {
             _transfer(user, stats.fee_to, stats.fee.clone());
+            RESERVE.with(|r| r.borrow_mut().fees_collected += stats.fee.clone());
         }
     });
 }
 
+fn _icp_fee_spent(fee: Tokens) {
+    RESERVE.with(|r| r.borrow_mut().icp_fees_spent += Nat::from(fee.e8s()));
+}
+
 fn _get_fee() -> Nat {
     STATS.with(|s| {
         let stats = s.borrow();
@@ -913,6 +1868,378 @@ fn _get_fee() -> Nat {
     })
 }
 
+struct IcpTransfer {
+    from: AccountIdentifier,
+    to: AccountIdentifier,
+    amount: Tokens,
+    memo: Option<Vec<u8>>,
+}
+
+async fn _get_transfer(block_height: BlockHeight) -> Result<IcpTransfer, TxError> {
+    let block = match ledger::get_block(_ledger_id(), block_height).await {
+        Ok(block) => block,
+        Err(BlockError::NotFound) => return Err(TxError::BlockNotFound),
+        Err(BlockError::LedgerUnreachable) => return Err(TxError::LedgerTrap),
+        Err(BlockError::ArchiveUnreachable) => return Err(TxError::ArchiveUnreachable),
+        Err(BlockError::DecodeFailure) => return Err(TxError::DecodeFailure),
+    };
+    match block.transaction.operation {
+        Some(Operate::Transfer {
+            from,
+            to,
+            amount,
+            fee: _,
+        }) => Ok(IcpTransfer {
+            from,
+            to,
+            amount,
+            memo: block.transaction.icrc1_memo,
+        }),
+        _ => Err(TxError::ErrorOperationStyle),
+    }
+}
+
+/// Checks that a ledger transfer paid the reserve (from `from` when given)
+/// and marks its block as used, so no mint path can credit it twice.
+fn _use_block(
+    block_height: BlockHeight,
+    transfer: &IcpTransfer,
+    from: Option<AccountIdentifier>,
+) -> Result<(), TxError> {
+    if from.map_or(false, |from| from != transfer.from) {
+        return Err(TxError::Unauthorized);
+    }
+    if AccountIdentifier::new(&ic::id(), &DEFAULT_SUBACCOUNT) != transfer.to {
+        return Err(TxError::ErrorTo);
+    }
+    if transfer.amount < _threshold() {
+        return Err(TxError::AmountTooSmall);
+    }
+    BLOCKS.with(|b| {
+        if b.borrow_mut().insert(block_height) {
+            Ok(())
+        } else {
+            Err(TxError::BlockUsed)
+        }
+    })
+}
+
+/// Credits `to` with the ICP of a used block and records the mint.
+fn _mint_block(
+    caller: Principal,
+    to: Principal,
+    block_height: BlockHeight,
+    amount: Tokens,
+) -> TxRecord {
+    let value = Nat::from(amount.e8s());
+    let user_balance = balance_of(to);
+    _balance_ins(to, user_balance + value.clone());
+    _supply_inc(value.clone());
+    let record = _record(
+        Some(caller),
+        Operation::Mint,
+        to,
+        to,
+        value,
+        Nat::from(0),
+        TransactionStatus::Succeeded,
+    );
+    BLOCKS.with(|b| {
+        b.borrow_mut()
+            .set_minted(block_height, record.index.clone())
+    });
+    record
+}
+
+async fn _withdraw(value: u64, to: AccountIdentifier) -> TxReceipt {
+    let fee = _icp_fee();
+    let amount = match Tokens::from_e8s(value).checked_sub(fee) {
+        Some(amount) if amount.e8s() > 0 && Tokens::from_e8s(value) >= _threshold() => amount,
+        _ => return Err(TxError::AmountTooSmall),
+    };
+    let caller = ic::caller();
+    let caller_balance = balance_of(caller);
+    let value_nat = Nat::from(value);
+    let total_supply = _supply_get();
+    if caller_balance.clone() < value_nat.clone() || total_supply < value_nat.clone() {
+        return Err(TxError::InsufficientBalance);
+    }
+    let args = TransferArgs {
+        memo: Memo(0x57444857),
+        amount,
+        fee,
+        from_subaccount: None,
+        to,
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: ic::time(),
+        }),
+    };
+    _balance_ins(caller, caller_balance.clone() - value_nat.clone());
+    _supply_dec(value_nat.clone());
+    // journal the burn before paying out, a lost reply leaves it pending for reconciliation
+    let id = _withdrawal_ins(caller, value_nat, args.clone());
+    let result = ledger::transfer(_ledger_id(), args).await;
+    match _withdrawal_settle(id, result) {
+        Some(withdrawal) if withdrawal.status == WithdrawalStatus::Sent => {
+            Ok(_record_withdrawal(withdrawal))
+        }
+        // a reconciliation pass may have settled it while the call was out
+        None => match _withdrawal_get(id) {
+            Withdrawal {
+                status: WithdrawalStatus::Sent | WithdrawalStatus::Confirmed,
+                tx_index: Some(index),
+                ..
+            } => Ok(index),
+            _ => Err(TxError::LedgerTrap),
+        },
+        _ => Err(TxError::LedgerTrap),
+    }
+}
+
+fn _withdrawal_ins(owner: Principal, amount: Nat, args: TransferArgs) -> u64 {
+    WITHDRAWALS.with(|w| {
+        let mut journal = w.borrow_mut();
+        let id = journal.next_id();
+        journal.insert(Withdrawal {
+            id,
+            owner,
+            amount,
+            args,
+            status: WithdrawalStatus::Pending,
+            block_index: None,
+            tx_index: None,
+            timestamp: ic::time(),
+        });
+        id
+    })
+}
+
+fn _withdrawal_get(id: u64) -> Withdrawal {
+    WITHDRAWALS.with(|w| w.borrow().get(id).expect("unknown withdrawal"))
+}
+
+fn _withdrawal_set(withdrawal: Withdrawal) {
+    WITHDRAWALS.with(|w| w.borrow_mut().insert(withdrawal));
+}
+
+/// Applies the ledger's answer for a pending withdrawal and returns it if it
+/// left the pending state. A rejected call tells nothing about the payout, so
+/// it keeps the withdrawal pending. Once the ledger no longer deduplicates the
+/// retry, only an operator can tell whether it was paid.
+fn _withdrawal_settle(id: u64, result: Result<TransferResult, CallError>) -> Option<Withdrawal> {
+    let mut withdrawal = _withdrawal_get(id);
+    if withdrawal.status != WithdrawalStatus::Pending {
+        return None;
+    }
+    match result {
+        Ok(Ok(block_index))
+        | Ok(Err(TransferError::TxDuplicate {
+            duplicate_of: block_index,
+        })) => {
+            withdrawal.status = WithdrawalStatus::Sent;
+            withdrawal.block_index = Some(block_index);
+            _icp_fee_spent(withdrawal.args.fee);
+        }
+        Ok(Err(TransferError::TxTooOld { .. })) => {
+            withdrawal.status = WithdrawalStatus::NeedsReview;
+        }
+        Err(_) => return None,
+        Ok(Err(_)) => _withdrawal_refund(&mut withdrawal),
+    }
+    _withdrawal_set(withdrawal.clone());
+    Some(withdrawal)
+}
+
+fn _withdrawal_refund(withdrawal: &mut Withdrawal) {
+    _balance_ins(
+        withdrawal.owner,
+        balance_of(withdrawal.owner) + withdrawal.amount.clone(),
+    );
+    _supply_inc(withdrawal.amount.clone());
+    withdrawal.status = WithdrawalStatus::Refunded;
+}
+
+fn _claim_get(owner: Principal) -> Option<PendingClaim> {
+    CLAIMS.with(|c| c.borrow().claims.get(&owner).cloned())
+}
+
+fn _claim_rem(owner: Principal) {
+    CLAIMS.with(|c| c.borrow_mut().claims.remove(&owner));
+}
+
+/// Applies the ledger's answer to the pending sweep of `owner`'s deposit and
+/// mints it once the sweep is known to be done. A rejected call tells nothing
+/// about the sweep, so it stays pending.
+fn _claim_settle(owner: Principal, result: Result<TransferResult, CallError>) -> TxReceipt {
+    let claim = match _claim_get(owner) {
+        Some(claim) => claim,
+        // settled by a concurrent call
+        None => return Err(TxError::BlockUsed),
+    };
+    match result {
+        Ok(Ok(block_height))
+        | Ok(Err(TransferError::TxDuplicate {
+            duplicate_of: block_height,
+        })) => {
+            _claim_rem(owner);
+            // the sweep is a transfer into the reserve, it must never be minted again
+            if !BLOCKS.with(|b| b.borrow_mut().insert(block_height)) {
+                return Err(TxError::BlockUsed);
+            }
+            _icp_fee_spent(claim.args.fee);
+            let record = _mint_block(owner, owner, block_height, claim.args.amount);
+            Ok(add_record(record))
+        }
+        Ok(Err(TransferError::TxTooOld { .. })) => {
+            CLAIMS.with(|c| {
+                if let Some(claim) = c.borrow_mut().claims.get_mut(&owner) {
+                    claim.needs_review = true;
+                }
+            });
+            Err(TxError::LedgerTrap)
+        }
+        Err(_) => Err(TxError::LedgerTrap),
+        Ok(Err(_)) => {
+            _claim_rem(owner);
+            Err(TxError::LedgerTrap)
+        }
+    }
+}
+
+/// Replays pending sweeps, so a lost reply is settled before the ledger stops
+/// deduplicating it. Like `_reconcile_withdrawals`, each pass picks up after
+/// the last owner the previous one looked at. Returns the number of deposits
+/// minted.
+async fn _reconcile_claims() -> usize {
+    if RECONCILING_CLAIMS.with(|r| r.replace(true)) {
+        return 0;
+    }
+    let after = CLAIMS_CURSOR.with(|c| *c.borrow());
+    let batch: Vec<(Principal, TransferArgs)> = CLAIMS.with(|c| {
+        c.borrow()
+            .iter_after(after)
+            .filter(|(_, claim)| !claim.needs_review)
+            .take(RECONCILE_BATCH)
+            .map(|(owner, claim)| (*owner, claim.args.clone()))
+            .collect()
+    });
+    if let Some((owner, _)) = batch.last() {
+        CLAIMS_CURSOR.with(|c| *c.borrow_mut() = Some(*owner));
+    }
+    let mut minted = 0;
+    for (owner, args) in batch {
+        let result = ledger::transfer(_ledger_id(), args).await;
+        if _claim_settle(owner, result).is_ok() {
+            minted += 1;
+        }
+    }
+    RECONCILING_CLAIMS.with(|r| *r.borrow_mut() = false);
+    minted
+}
+
+fn _record_withdrawal(mut withdrawal: Withdrawal) -> Nat {
+    let record = _record(
+        Some(withdrawal.owner),
+        Operation::Burn,
+        withdrawal.owner,
+        withdrawal.owner,
+        withdrawal.amount.clone(),
+        Nat::from(0),
+        TransactionStatus::Succeeded,
+    );
+    let index = add_record(record);
+    withdrawal.tx_index = Some(index.clone());
+    _withdrawal_set(withdrawal);
+    index
+}
+
+/// Retries pending payouts with their original `created_at_time`, so the
+/// ledger deduplicates any transfer that did go through, and confirms sent
+/// payouts against the ledger. Each pass picks up after the last withdrawal
+/// the previous one looked at, so entries that keep failing don't hold back
+/// the others. Returns the number of withdrawals that moved on.
+async fn _reconcile_withdrawals() -> usize {
+    if RECONCILING.with(|r| r.replace(true)) {
+        return 0;
+    }
+    let batch: Vec<Withdrawal> = WITHDRAWALS.with(|w| {
+        let start = RECONCILE_CURSOR.with(|c| *c.borrow());
+        w.borrow()
+            .iter_from(start)
+            .filter(|withdrawal| {
+                withdrawal.status == WithdrawalStatus::Pending
+                    || withdrawal.status == WithdrawalStatus::Sent
+            })
+            .take(RECONCILE_BATCH)
+            .collect()
+    });
+    if let Some(last) = batch.last() {
+        RECONCILE_CURSOR.with(|c| *c.borrow_mut() = last.id + 1);
+    }
+    let mut settled = 0;
+    for withdrawal in batch {
+        match withdrawal.status {
+            WithdrawalStatus::Pending => {
+                let result = ledger::transfer(_ledger_id(), withdrawal.args.clone()).await;
+                if let Some(withdrawal) = _withdrawal_settle(withdrawal.id, result) {
+                    settled += 1;
+                    if withdrawal.status == WithdrawalStatus::Sent {
+                        _record_withdrawal(withdrawal);
+                    }
+                }
+            }
+            WithdrawalStatus::Sent => {
+                let block_index = withdrawal.block_index.unwrap();
+                if let Ok(block) = ledger::get_block(_ledger_id(), block_index).await {
+                    let paid = match block.transaction.operation {
+                        Some(Operate::Transfer { to, amount, .. }) => {
+                            to == withdrawal.args.to && amount == withdrawal.args.amount
+                        }
+                        _ => false,
+                    };
+                    let mut withdrawal = _withdrawal_get(withdrawal.id);
+                    withdrawal.status = if paid {
+                        WithdrawalStatus::Confirmed
+                    } else {
+                        WithdrawalStatus::NeedsReview
+                    };
+                    _withdrawal_set(withdrawal);
+                    settled += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    RECONCILING.with(|r| *r.borrow_mut() = false);
+    settled
+}
+
+fn _ledger_config() -> LedgerConfig {
+    CONFIG.with(|c| c.borrow().clone())
+}
+
+fn _ledger_id() -> Principal {
+    CONFIG.with(|c| c.borrow().ledger_id)
+}
+
+fn _icp_fee() -> Tokens {
+    CONFIG.with(|c| c.borrow().icp_fee)
+}
+
+fn _threshold() -> Tokens {
+    CONFIG.with(|c| c.borrow().threshold)
+}
+
+async fn _sync_icp_fee() -> Result<Tokens, TxError> {
+    match ledger::transfer_fee(_ledger_id()).await {
+        Ok(fee) => {
+            CONFIG.with(|c| c.borrow_mut().icp_fee = fee);
+            Ok(fee)
+        }
+        Err(_) => Err(TxError::LedgerTrap),
+    }
+}
+
 fn _get_owner() -> Principal {
     STATS.with(|s| {
         let stats = s.borrow();
@@ -920,110 +2247,190 @@ fn _get_owner() -> Principal {
     })
 }
 
-async fn add_record(
-    caller: Option<Principal>,
-    op: Operation,
-    from: Principal,
-    to: Principal,
-    amount: Nat,
-    fee: Nat,
-    timestamp: u64,
-    status: TransactionStatus,
-) -> TxReceipt {
-    insert_into_cap(Into::<IndefiniteEvent>::into(Into::<Event>::into(Into::<
-        TypedEvent<DIP20Details>,
-    >::into(
-        TxRecord {
-            caller,
-            index: Nat::from(0),
-            from,
-            to,
-            amount: Nat::from(amount),
-            fee: Nat::from(fee),
-            timestamp: Int::from(timestamp),
-            status,
-            operation: op,
-        },
-    ))))
-    .await
+/// Writes the record to the configured history sinks and returns its local
+/// index. The transaction is already committed, so the caller never waits on
+/// a sink nor sees its failures.
+fn add_record(record: TxRecord) -> Nat {
+    _history_sink().record(&record);
+    record.index
 }
 
-pub async fn insert_into_cap(ie: IndefiniteEvent) -> TxReceipt {
-    let mut event = ie;
-    TXLOG.with(|t| {
-        let mut tx_log = t.borrow_mut();
-        if let Some(failed_ie) = tx_log.ie_records.pop_front() {
-            event = failed_ie;
+fn _history_sink() -> FanOut {
+    SINKS.with(|s| {
+        FanOut(
+            s.borrow()
+                .iter()
+                .map(|backend| -> Box<dyn HistorySink> {
+                    match backend {
+                        HistoryBackend::Cap => Box::new(CapSink),
+                        HistoryBackend::Local => Box::new(LocalSink),
+                        HistoryBackend::Noop => Box::new(NoopSink),
+                    }
+                })
+                .collect(),
+        )
+    })
+}
+
+fn _has_backend(backend: HistoryBackend) -> bool {
+    SINKS.with(|s| s.borrow().contains(&backend))
+}
+
+/// Queues records in the CAP outbox, flushed in order in the background.
+struct CapSink;
+
+impl HistorySink for CapSink {
+    fn record(&self, record: &TxRecord) {
+        _cap_enqueue(record);
+        if _cap_flush_due(ic::time()) {
+            ic_cdk::block_on(async {
+                _flush_cap_queue().await;
+            });
         }
-    });
-    insert_into_cap_priv(event).await
+    }
 }
 
-async fn insert_into_cap_priv(ie: IndefiniteEvent) -> TxReceipt {
-    let insert_res = insert(ie.clone())
-        .await
-        .map(|tx_id| Nat::from(tx_id))
-        .map_err(|_| TxError::Other);
+fn _cap_enqueue(record: &TxRecord) {
+    let event = Into::<IndefiniteEvent>::into(Into::<Event>::into(
+        Into::<TypedEvent<DIP20Details>>::into(record.clone()),
+    ));
+    TXLOG.with(|t| t.borrow_mut().push_back(event));
+}
 
-    if insert_res.is_err() {
-        TXLOG.with(|t| {
-            let mut tx_log = t.borrow_mut();
-            tx_log.ie_records.push_back(ie.clone());
-        });
+/// Appends records to the in-canister log and its per-user index.
+struct LocalSink;
+
+impl HistorySink for LocalSink {
+    fn record(&self, record: &TxRecord) {
+        HISTORY.with(|h| h.borrow_mut().push(record.clone()));
     }
+}
+
+fn _cap_flush_due(now: u64) -> bool {
+    let queued = TXLOG.with(|t| !t.borrow().is_empty());
+    queued
+        && CAP_STATUS.with(|c| {
+            let status = c.borrow();
+            !status.flushing && now >= status.next_retry
+        })
+}
 
-    insert_res
+/// Sends queued events to CAP in order, one at a time so a failure never lets
+/// a later event through first. Stops at the first failure and backs off
+/// exponentially. Returns the number of events indexed.
+async fn _flush_cap_queue() -> usize {
+    if CAP_STATUS.with(|c| c.borrow().flushing) {
+        return 0;
+    }
+    CAP_STATUS.with(|c| c.borrow_mut().flushing = true);
+    let mut indexed = 0;
+    while indexed < CAP_FLUSH_BATCH {
+        let event = match TXLOG.with(|t| t.borrow().front()) {
+            Some(event) => event,
+            None => break,
+        };
+        match insert(event).await {
+            Ok(_) => {
+                TXLOG.with(|t| t.borrow_mut().pop_front());
+                CAP_STATUS.with(|c| {
+                    let mut status = c.borrow_mut();
+                    status.indexed += 1;
+                    status.consecutive_failures = 0;
+                });
+                indexed += 1;
+            }
+            Err(_) => {
+                CAP_STATUS.with(|c| {
+                    let mut status = c.borrow_mut();
+                    status.failures += 1;
+                    status.consecutive_failures += 1;
+                    let backoff = (CAP_RETRY_BASE << (status.consecutive_failures - 1).min(16))
+                        .min(CAP_RETRY_MAX);
+                    status.next_retry = ic::time() + backoff;
+                });
+                break;
+            }
+        }
+    }
+    CAP_STATUS.with(|c| c.borrow_mut().flushing = false);
+    indexed
 }
 
 /* MISC FNS */
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let stats = STATS.with(|s| s.borrow().clone());
-    let balances = BALANCES.with(|b| b.borrow().clone());
-    let allows = ALLOWS.with(|a| a.borrow().clone());
-    let blocks = BLOCKS.with(|b| b.borrow().clone());
-    let tx_log = TXLOG.with(|t| t.borrow().clone());
-    ic::stable_store((
-        stats,
-        balances,
-        allows,
-        blocks,
-        tx_log,
-        CapEnv::to_archive(),
-    ))
-    .unwrap();
+    // balances, allowances, used blocks, the CAP outbox, the local log and
+    // the withdrawal journal live in stable structures and stay where they are
+    upgrade::save(upgrade::State {
+        stats: STATS.with(|s| s.borrow().clone()),
+        cap_env: CapEnv::to_archive(),
+        claims: CLAIMS.with(|c| c.borrow().clone()),
+        reserve: RESERVE.with(|r| r.borrow().clone()),
+        config: CONFIG.with(|c| c.borrow().clone()),
+        dedup: DEDUP.with(|d| d.borrow().clone()),
+        sinks: SINKS.with(|s| s.borrow().clone()),
+        genesis: GENESIS.with(|g| g.borrow().clone()),
+        roles: ROLES.with(|r| r.borrow().clone()),
+        paused: PAUSED.with(|p| *p.borrow()),
+        restoring: RESTORING.with(|r| r.borrow().clone()),
+    });
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (
-        metadata_stored,
-        balances_stored,
-        allowances_stored,
-        blocks_stored,
-        tx_log_stored,
+    let upgrade::State {
+        stats: metadata_stored,
         cap_env,
-    ): (StatsData, Balances, Allowances, UsedBlocks, TxLog, CapEnv) = ic::stable_restore().unwrap();
+        claims: claims_stored,
+        reserve: reserve_stored,
+        config: config_stored,
+        dedup: dedup_stored,
+        sinks: sinks_stored,
+        genesis: genesis_stored,
+        roles: roles_stored,
+        paused: paused_stored,
+        restoring: restoring_stored,
+    } = upgrade::load();
     STATS.with(|s| {
         let mut stats = s.borrow_mut();
         *stats = metadata_stored;
     });
-    BALANCES.with(|b| {
-        let mut balances = b.borrow_mut();
-        *balances = balances_stored;
+    CLAIMS.with(|c| {
+        let mut claims = c.borrow_mut();
+        *claims = claims_stored;
     });
-    ALLOWS.with(|a| {
-        let mut allowances = a.borrow_mut();
-        *allowances = allowances_stored;
+    RESERVE.with(|r| {
+        let mut reserve = r.borrow_mut();
+        *reserve = reserve_stored;
     });
-    BLOCKS.with(|b| {
-        let mut blocks = b.borrow_mut();
-        *blocks = blocks_stored;
+    CONFIG.with(|c| {
+        let mut config = c.borrow_mut();
+        *config = config_stored;
+    });
+    DEDUP.with(|d| {
+        let mut dedup = d.borrow_mut();
+        *dedup = dedup_stored;
+    });
+    SINKS.with(|s| {
+        let mut sinks = s.borrow_mut();
+        *sinks = sinks_stored;
+    });
+    GENESIS.with(|g| {
+        let mut genesis = g.borrow_mut();
+        *genesis = genesis_stored;
+    });
+    ROLES.with(|r| {
+        let mut roles = r.borrow_mut();
+        *roles = roles_stored;
+    });
+    PAUSED.with(|p| {
+        let mut paused = p.borrow_mut();
+        *paused = paused_stored;
     });
-    TXLOG.with(|t| {
-        let mut tx_log = t.borrow_mut();
-        *tx_log = tx_log_stored;
+    RESTORING.with(|r| {
+        let mut restoring = r.borrow_mut();
+        *restoring = restoring_stored;
     });
     CapEnv::load_from_archive(cap_env);
 }
@@ -0,0 +1,288 @@
+/**
+* Module     : stable.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use crate::icrc1::AccountKey;
+use crate::icrc2::Allowance;
+use crate::ledger::Subaccount;
+use crate::Withdrawal;
+use candid::{CandidType, Deserialize, Nat};
+use cap_sdk::IndefiniteEvent;
+use cap_std::dip20::TxRecord;
+use ic_kit::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, Memory as _, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// the state that isn't kept in stable structures, written on upgrade
+const UPGRADES: MemoryId = MemoryId::new(0);
+pub const BALANCES: MemoryId = MemoryId::new(1);
+pub const ALLOWANCES: MemoryId = MemoryId::new(2);
+pub const BLOCK_WATERMARK: MemoryId = MemoryId::new(3);
+pub const BLOCK_RANGES: MemoryId = MemoryId::new(4);
+pub const BLOCK_MINTED: MemoryId = MemoryId::new(5);
+pub const OUTBOX_EVENTS: MemoryId = MemoryId::new(6);
+pub const OUTBOX_TAIL: MemoryId = MemoryId::new(7);
+pub const HISTORY_RECORDS: MemoryId = MemoryId::new(8);
+pub const USER_TXS: MemoryId = MemoryId::new(9);
+pub const WITHDRAWALS: MemoryId = MemoryId::new(10);
+pub const USER_WITHDRAWALS: MemoryId = MemoryId::new(11);
+pub const WITHDRAWALS_PENDING: MemoryId = MemoryId::new(12);
+
+// written by the memory manager at the start of stable memory
+const MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Whether stable memory is laid out by the memory manager. Older releases
+/// wrote a single candid tuple instead, which has to be read before anything
+/// touches the memory manager.
+pub fn is_managed() -> bool {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    &magic == MANAGER_MAGIC
+}
+
+/// Writes the upgrade state, prefixed with its length.
+pub fn save<T: CandidType>(state: T) {
+    let bytes = candid::encode_one(state).expect("unable to encode the upgrade state");
+    let mut memory = memory(UPGRADES);
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|_| writer.write(&bytes))
+        .expect("unable to write the upgrade state");
+}
+
+/// Reads back the candid bytes written by `save`.
+pub fn restore() -> Vec<u8> {
+    let memory = memory(UPGRADES);
+    let mut len = [0; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    memory.read(8, &mut bytes);
+    bytes
+}
+
+fn encode<T: CandidType>(value: &T) -> Cow<[u8]> {
+    Cow::Owned(candid::encode_one(value).expect("unable to encode a stable value"))
+}
+
+fn decode<T: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> T {
+    candid::decode_one(bytes).expect("unable to decode a stable value")
+}
+
+// principal bytes padded to 29, their length, then the subaccount, so the
+// encoding sorts like the key
+const PRINCIPAL_SIZE: usize = 29;
+const ACCOUNT_KEY_SIZE: usize = PRINCIPAL_SIZE + 1 + 32;
+
+fn encode_principal(principal: &Principal, bytes: &mut [u8]) {
+    let principal = principal.as_slice();
+    bytes[..principal.len()].copy_from_slice(principal);
+    bytes[PRINCIPAL_SIZE] = principal.len() as u8;
+}
+
+fn decode_principal(bytes: &[u8]) -> Principal {
+    let len = bytes[PRINCIPAL_SIZE] as usize;
+    Principal::from_slice(&bytes[..len])
+}
+
+impl Storable for AccountKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![0; ACCOUNT_KEY_SIZE];
+        encode_principal(&self.0, &mut bytes);
+        bytes[PRINCIPAL_SIZE + 1..].copy_from_slice(&(self.1).0);
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut subaccount = [0; 32];
+        subaccount.copy_from_slice(&bytes[PRINCIPAL_SIZE + 1..ACCOUNT_KEY_SIZE]);
+        AccountKey(decode_principal(&bytes), Subaccount(subaccount))
+    }
+}
+
+impl BoundedStorable for AccountKey {
+    const MAX_SIZE: u32 = ACCOUNT_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Key of an allowance, all the allowances of an owner are next to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AllowanceKey {
+    pub owner: AccountKey,
+    pub spender: AccountKey,
+}
+
+impl AllowanceKey {
+    /// Lower bound of the allowances given by `owner`.
+    pub fn first_of(owner: AccountKey) -> Self {
+        AllowanceKey {
+            owner,
+            spender: AccountKey(Principal::from_slice(&[]), Subaccount([0; 32])),
+        }
+    }
+}
+
+impl Storable for AllowanceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.owner.to_bytes().into_owned();
+        bytes.extend_from_slice(&self.spender.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        AllowanceKey {
+            owner: AccountKey::from_bytes(Cow::Borrowed(&bytes[..ACCOUNT_KEY_SIZE])),
+            spender: AccountKey::from_bytes(Cow::Borrowed(&bytes[ACCOUNT_KEY_SIZE..])),
+        }
+    }
+}
+
+impl BoundedStorable for AllowanceKey {
+    const MAX_SIZE: u32 = 2 * ACCOUNT_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+pub struct StableNat(pub Nat);
+
+impl Storable for StableNat {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StableNat(decode(&bytes))
+    }
+}
+
+impl BoundedStorable for StableNat {
+    // room for 256-bit amounts
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Allowance {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Allowance {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+pub struct StableEvent(pub IndefiniteEvent);
+
+impl Storable for StableEvent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StableEvent(decode(&bytes))
+    }
+}
+
+impl BoundedStorable for StableEvent {
+    // DIP20 events are a few hundred bytes
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// principal as in an account key, then the big-endian index
+const USER_TX_KEY_SIZE: usize = PRINCIPAL_SIZE + 1 + 8;
+
+/// Key of the user indexes, the transactions or withdrawals of a principal
+/// are next to each other, in index order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct UserTxKey {
+    pub user: Principal,
+    pub index: u64,
+}
+
+impl UserTxKey {
+    /// Lower bound of the transactions of `user`.
+    pub fn first_of(user: Principal) -> Self {
+        UserTxKey { user, index: 0 }
+    }
+}
+
+impl Storable for UserTxKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = vec![0; USER_TX_KEY_SIZE];
+        encode_principal(&self.user, &mut bytes);
+        bytes[PRINCIPAL_SIZE + 1..].copy_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut index = [0; 8];
+        index.copy_from_slice(&bytes[PRINCIPAL_SIZE + 1..USER_TX_KEY_SIZE]);
+        UserTxKey {
+            user: decode_principal(&bytes),
+            index: u64::from_be_bytes(index),
+        }
+    }
+}
+
+impl BoundedStorable for UserTxKey {
+    const MAX_SIZE: u32 = USER_TX_KEY_SIZE as u32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+pub struct StableRecord(pub TxRecord);
+
+impl Storable for StableRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StableRecord(decode(&bytes))
+    }
+}
+
+impl BoundedStorable for StableRecord {
+    // DIP20 records are a few hundred bytes
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Withdrawal {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        encode(self)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode(&bytes)
+    }
+}
+
+impl BoundedStorable for Withdrawal {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
@@ -0,0 +1,295 @@
+/**
+* Module     : upgrade.rs
+* Copyright  : 2022 Psychedelic
+* License    : Apache 2.0 with LLVM Exception
+* Maintainer : Ossian Mapes <oz@fleek.co>
+* Stability  : Experimental
+*/
+use crate::auth::Roles;
+use crate::backup::Restore;
+use crate::dedup::DedupIndex;
+use crate::history::HistoryBackend;
+use crate::icrc1::AccountKey;
+use crate::icrc2::Allowance;
+use crate::ledger::{BlockHeight, DEFAULT_SUBACCOUNT};
+use crate::stable::{self, AllowanceKey, StableNat};
+use crate::{
+    ClaimJournal, Genesis, LedgerConfig, ReserveData, StatsData, ALLOWS, BALANCES, BLOCKS, TXLOG,
+};
+use candid::{CandidType, Deserialize, Nat};
+use cap_sdk::{CapEnv, IndefiniteEvent};
+use ic_kit::{ic, Principal};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Version of the layout written by `pre_upgrade`.
+///
+/// Changing `State`, or any type stored in it, once a release has shipped
+/// means bumping the version, keeping the released layout below and adding
+/// the step that converts it.
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// What `pre_upgrade` writes: the state, encoded in the layout of `version`.
+#[derive(CandidType, Deserialize)]
+struct Envelope {
+    version: u32,
+    state: Vec<u8>,
+}
+
+/// Heap state kept across upgrades. Balances, allowances, used blocks, the
+/// CAP outbox, the local log and the withdrawal journal are in stable
+/// structures and aren't part of it. What is left doesn't grow with the
+/// number of transactions: the dedup index only holds the last 24 hours.
+#[derive(CandidType, Deserialize)]
+pub struct State {
+    pub stats: StatsData,
+    pub cap_env: CapEnv,
+    pub claims: ClaimJournal,
+    pub reserve: ReserveData,
+    pub config: LedgerConfig,
+    pub dedup: DedupIndex,
+    pub sinks: Vec<HistoryBackend>,
+    pub genesis: Genesis,
+    pub roles: Roles,
+    pub paused: bool,
+    pub restoring: Option<Restore>,
+}
+
+/// CAP outbox of the 2.0.0 release.
+#[derive(CandidType, Default, Deserialize, Clone)]
+struct TxLog {
+    ie_records: VecDeque<IndefiniteEvent>,
+}
+
+// layout of the 2.0.0 release, a tuple filling raw stable memory
+type StateV1 = (
+    StatsData,
+    HashMap<Principal, Nat>,
+    HashMap<Principal, HashMap<Principal, Nat>>,
+    HashSet<BlockHeight>,
+    TxLog,
+    CapEnv,
+);
+
+enum Stored {
+    V1(StateV1),
+    V2(State),
+}
+
+pub fn save(state: State) {
+    let state = candid::encode_one(state).expect("unable to encode the upgrade state");
+    stable::save(Envelope {
+        version: SCHEMA_VERSION,
+        state,
+    });
+}
+
+/// Reads the state written by any earlier release and brings it up to the
+/// current layout.
+pub fn load() -> State {
+    migrate(read())
+}
+
+fn migrate(stored: Stored) -> State {
+    match stored {
+        Stored::V1(state) => v1_to_v2(state),
+        Stored::V2(state) => state,
+    }
+}
+
+fn read() -> Stored {
+    // the 2.0.0 tuple is decoded before any stable structure is touched,
+    // since the memory manager takes over stable memory on first use
+    if !stable::is_managed() {
+        return Stored::V1(ic::stable_restore().expect("unable to decode the 2.0.0 state"));
+    }
+    match dispatch(stable::restore()) {
+        Ok(stored) => stored,
+        Err(e) => ic_cdk::api::trap(&e),
+    }
+}
+
+/// Decodes what `save` wrote, in the layout of the version it was written in.
+fn dispatch(bytes: Vec<u8>) -> Result<Stored, String> {
+    let envelope: Envelope = decode(&bytes)?;
+    match envelope.version {
+        2 => Ok(Stored::V2(decode(&envelope.state)?)),
+        version if version > SCHEMA_VERSION => Err(format!(
+            "Error: state version {} is newer than {}",
+            version, SCHEMA_VERSION
+        )),
+        version => Err(format!("Error: unknown state version {}", version)),
+    }
+}
+
+fn decode<T: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T, String> {
+    candid::decode_one(bytes).map_err(|e| format!("Error: unable to decode the state: {}", e))
+}
+
+/// Moves the 2.0.0 balances, allowances, used blocks and CAP outbox to their
+/// stable structures. Principals become their default accounts and
+/// allowances don't expire.
+///
+/// Older transactions were only recorded in CAP, so the local log starts at
+/// the history size. The genesis could only be sent through `setGenesis`, so
+/// it is assumed sent rather than risking a duplicate in CAP.
+fn v1_to_v2(state: StateV1) -> State {
+    let (stats, balances, allowances, blocks, tx_log, cap_env) = state;
+    BALANCES.with(|b| {
+        let mut stored = b.borrow_mut();
+        for (owner, balance) in balances {
+            stored.insert(AccountKey(owner, DEFAULT_SUBACCOUNT), StableNat(balance));
+        }
+    });
+    ALLOWS.with(|a| {
+        let mut stored = a.borrow_mut();
+        for (owner, inner) in allowances {
+            for (spender, value) in inner {
+                let key = AllowanceKey {
+                    owner: AccountKey(owner, DEFAULT_SUBACCOUNT),
+                    spender: AccountKey(spender, DEFAULT_SUBACCOUNT),
+                };
+                let allowance = Allowance {
+                    allowance: value,
+                    expires_at: None,
+                };
+                stored.insert(key, allowance);
+            }
+        }
+    });
+    BLOCKS.with(|b| b.borrow_mut().migrate(blocks));
+    TXLOG.with(|t| {
+        let mut outbox = t.borrow_mut();
+        for event in tx_log.ie_records {
+            outbox.push_back(event);
+        }
+    });
+    State {
+        stats,
+        cap_env,
+        claims: ClaimJournal::default(),
+        reserve: ReserveData::default(),
+        config: LedgerConfig::default(),
+        dedup: DedupIndex::default(),
+        sinks: vec![HistoryBackend::Cap, HistoryBackend::Local],
+        genesis: Genesis {
+            record: None,
+            recorded: true,
+        },
+        roles: Roles::default(),
+        paused: false,
+        restoring: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HISTORY;
+
+    fn alice() -> Principal {
+        Principal::from_slice(&[1])
+    }
+
+    fn bob() -> Principal {
+        Principal::from_slice(&[2])
+    }
+
+    fn stats(history_size: usize) -> StatsData {
+        StatsData {
+            history_size,
+            ..StatsData::default()
+        }
+    }
+
+    fn envelope(version: u32, state: Vec<u8>) -> Vec<u8> {
+        candid::encode_one(Envelope { version, state }).unwrap()
+    }
+
+    #[test]
+    fn v1_to_v2_moves_the_release_state_to_stable_memory() {
+        let mut balances = HashMap::new();
+        balances.insert(alice(), Nat::from(10));
+        let mut allowances = HashMap::new();
+        allowances.insert(alice(), vec![(bob(), Nat::from(5))].into_iter().collect());
+        let state = Stored::V1((
+            stats(4),
+            balances,
+            allowances,
+            vec![3, 4, 7].into_iter().collect(),
+            TxLog::default(),
+            CapEnv::default(),
+        ));
+
+        let state = migrate(state);
+
+        assert_eq!(state.stats.history_size, 4);
+        assert_eq!(
+            state.sinks,
+            vec![HistoryBackend::Cap, HistoryBackend::Local]
+        );
+        assert!(state.genesis.record.is_none() && state.genesis.recorded);
+        assert!(!state.paused && state.restoring.is_none());
+        let balance = BALANCES.with(|b| b.borrow().get(&AccountKey(alice(), DEFAULT_SUBACCOUNT)));
+        assert_eq!(balance.map(|balance| balance.0), Some(Nat::from(10)));
+        let key = AllowanceKey {
+            owner: AccountKey(alice(), DEFAULT_SUBACCOUNT),
+            spender: AccountKey(bob(), DEFAULT_SUBACCOUNT),
+        };
+        let allowance = ALLOWS.with(|a| a.borrow().get(&key)).unwrap();
+        assert_eq!(allowance.allowance, Nat::from(5));
+        assert_eq!(allowance.expires_at, None);
+        BLOCKS.with(|b| {
+            let blocks = b.borrow();
+            assert!(blocks.contains(3) && blocks.contains(4) && blocks.contains(7));
+            assert!(!blocks.contains(5));
+        });
+        assert!(TXLOG.with(|t| t.borrow().is_empty()));
+        // older transactions were only in CAP
+        assert!(HISTORY.with(|h| h.borrow().get(0).is_none()));
+    }
+
+    #[test]
+    fn dispatch_reads_the_current_version() {
+        let state = State {
+            stats: stats(3),
+            cap_env: CapEnv::default(),
+            claims: ClaimJournal::default(),
+            reserve: ReserveData::default(),
+            config: LedgerConfig::default(),
+            dedup: DedupIndex::default(),
+            sinks: vec![HistoryBackend::Local],
+            genesis: Genesis::default(),
+            roles: Roles::default(),
+            paused: true,
+            restoring: None,
+        };
+        let bytes = envelope(SCHEMA_VERSION, candid::encode_one(state).unwrap());
+
+        match dispatch(bytes) {
+            Ok(Stored::V2(state)) => {
+                assert_eq!(state.stats.history_size, 3);
+                assert!(state.paused);
+            }
+            _ => panic!("expected the current layout"),
+        }
+    }
+
+    #[test]
+    fn dispatch_refuses_a_newer_version() {
+        let bytes = envelope(SCHEMA_VERSION + 1, Vec::new());
+
+        assert_eq!(
+            dispatch(bytes).err(),
+            Some(format!(
+                "Error: state version {} is newer than {}",
+                SCHEMA_VERSION + 1,
+                SCHEMA_VERSION
+            ))
+        );
+    }
+
+    #[test]
+    fn dispatch_refuses_a_corrupt_envelope() {
+        assert!(dispatch(vec![1, 2, 3]).is_err());
+    }
+}